@@ -0,0 +1,47 @@
+// Compares `write_f64_row()` with writing the same rows by `write_key_and_f64()`.
+// The chunks are uncompressed so that the encoding isn't hidden behind gzip.
+// Run with `cargo bench`
+extern crate td_client;
+
+use std::time::{Duration, Instant};
+use td_client::table_import::{ChunkCodec, TableImportWritableChunk};
+
+const ROWS: u64 = 500_000;
+const KEYS: [&'static str; 8] = ["cpu", "mem", "disk", "net_in", "net_out", "load1", "load5",
+                                 "load15"];
+
+fn secs(elapsed: Duration) -> f64 {
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+fn main() {
+    let mut values = [0.0; 8];
+
+    let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+    let started_at = Instant::now();
+    for i in 0..ROWS {
+        for (j, value) in values.iter_mut().enumerate() {
+            *value = (i + j as u64) as f64 / 7.0;
+        }
+        chunk.next_row(KEYS.len() as u32).unwrap();
+        for (key, value) in KEYS.iter().zip(values.iter()) {
+            chunk.write_key_and_f64(key, *value).unwrap();
+        }
+    }
+    chunk.close().unwrap();
+    let per_field = secs(started_at.elapsed());
+
+    let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+    let started_at = Instant::now();
+    for i in 0..ROWS {
+        for (j, value) in values.iter_mut().enumerate() {
+            *value = (i + j as u64) as f64 / 7.0;
+        }
+        chunk.write_f64_row(&KEYS, &values).unwrap();
+    }
+    chunk.close().unwrap();
+    let per_row = secs(started_at.elapsed());
+
+    println!("{} rows of {} doubles: write_key_and_f64 {:.3} s, write_f64_row {:.3} s",
+             ROWS, KEYS.len(), per_field, per_row);
+}
@@ -0,0 +1,28 @@
+// Measures how fast small fields are written to a chunk, which is the hot loop of
+// ingestion. Run with `cargo bench`
+extern crate td_client;
+
+use std::time::Instant;
+use td_client::table_import::TableImportWritableChunk;
+
+const ROWS: u64 = 500_000;
+
+fn main() {
+    let mut chunk = TableImportWritableChunk::new().unwrap();
+    let started_at = Instant::now();
+    for i in 0..ROWS {
+        chunk.next_row(6).unwrap();
+        chunk.write_key_and_u64("time", 1_500_000_000 + i).unwrap();
+        chunk.write_key_and_i64("id", i as i64).unwrap();
+        chunk.write_key_and_f64("score", i as f64 / 3.0).unwrap();
+        chunk.write_key_and_bool("active", i % 2 == 0).unwrap();
+        chunk.write_key_and_str("name", "td-client").unwrap();
+        chunk.write_key_and_nil("note").unwrap();
+    }
+    let readable = chunk.close().unwrap();
+    let elapsed = started_at.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{} rows ({} fields) in {:.3} s: {:.0} fields/s",
+             ROWS, ROWS * 6, secs, (ROWS * 6) as f64 / secs);
+    drop(readable);
+}
@@ -1,6 +1,8 @@
 use std::error::Error;
 use std::fmt;
 
+use table_import::TableImportChunkError;
+
 #[derive(Debug)]
 pub struct InvalidArgument {
     pub key: String,
@@ -18,11 +20,52 @@ impl Error for InvalidArgument {
         "invalid argument"
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn source(&self) -> Option<&(Error + 'static)> {
         None
     }
 }
 
+// Error response of the API. TD returns a JSON like `{"error":"...","text":"..."}`,
+// where `error` tells the kind of the error and `text` describes it.
+// `body` holds the response body as is, which isn't necessarily UTF-8
+#[derive(Debug)]
+pub struct ApiErrorBody {
+    pub message: String,
+    pub error_code: Option<String>,
+    pub body: Vec<u8>
+}
+
+impl ApiErrorBody {
+    pub fn parse(body: String) -> ApiErrorBody {
+        ApiErrorBody::from_bytes(body.into_bytes())
+    }
+
+    pub fn from_bytes(body: Vec<u8>) -> ApiErrorBody {
+        let text = String::from_utf8_lossy(&body).into_owned();
+        let json = ::rustc_serialize::json::Json::from_str(text.as_str()).ok();
+        let pick = |key: &str| json.as_ref().
+            and_then(|json| json.find(key)).
+            and_then(|value| value.as_string()).
+            map(|value| value.to_string());
+        let error_code = pick("error");
+        let message = pick("text").or(error_code.clone()).unwrap_or(text);
+        ApiErrorBody {
+            message: message,
+            error_code: error_code,
+            body: body
+        }
+    }
+}
+
+impl fmt::Display for ApiErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.error_code {
+            Some(ref error_code) => write!(f, "{}: {}", error_code, self.message),
+            None => write!(f, "{}", self.message)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum TreasureDataError {
     JsonDecodeError(::rustc_serialize::json::DecoderError),
@@ -31,9 +74,21 @@ pub enum TreasureDataError {
     MsgpackUnexpectedValueError(::rmpv::Value),
     TimeStampParseError(::chrono::ParseError),
     HttpError(::reqwest::Error),
-    ApiError(::reqwest::StatusCode, String),
+    ApiError(::reqwest::StatusCode, ApiErrorBody),
+    UnauthorizedError(String),
+    DnsError(::std::io::Error),
     InvalidArgumentError(InvalidArgument),
-    IoError(::std::io::Error)
+    ChunkError(TableImportChunkError),
+    JobFailedError(u64),
+    JobTimeoutError(u64),
+    FromRowError(String),
+    NotFoundError(String),
+    NotEmptyError(String),
+    IoError(::std::io::Error),
+    // The last error of a request given up since its `RetryBudget` ran out
+    RetryBudgetExhaustedError(Box<TreasureDataError>),
+    #[cfg(feature = "arrow")]
+    ArrowError(::arrow_schema::ArrowError)
 }
 
 impl From<::rustc_serialize::json::DecoderError> for TreasureDataError {
@@ -66,12 +121,25 @@ impl From<InvalidArgument> for TreasureDataError {
     }
 }
 
+impl From<TableImportChunkError> for TreasureDataError {
+    fn from(err: TableImportChunkError) -> TreasureDataError {
+        TreasureDataError::ChunkError(err)
+    }
+}
+
 impl From<::std::io::Error> for TreasureDataError {
     fn from(err: ::std::io::Error) -> TreasureDataError {
         TreasureDataError::IoError(err)
     }
 }
 
+#[cfg(feature = "arrow")]
+impl From<::arrow_schema::ArrowError> for TreasureDataError {
+    fn from(err: ::arrow_schema::ArrowError) -> TreasureDataError {
+        TreasureDataError::ArrowError(err)
+    }
+}
+
 impl From<::chrono::ParseError> for TreasureDataError {
     fn from(err: ::chrono::ParseError) -> TreasureDataError {
         TreasureDataError::TimeStampParseError(err)
@@ -96,12 +164,30 @@ impl Error for TreasureDataError {
             TreasureDataError::HttpError(ref x) => x.description(),
             TreasureDataError::ApiError(..) =>
                 "recieved unexpected status code",
+            TreasureDataError::UnauthorizedError(..) =>
+                "not authorized with the API key",
+            TreasureDataError::DnsError(ref x) => x.description(),
             TreasureDataError::InvalidArgumentError(ref x) => x.description(),
-            TreasureDataError::IoError(ref x) => x.description()
+            TreasureDataError::ChunkError(ref x) => x.description(),
+            TreasureDataError::JobFailedError(..) =>
+                "job didn't finish successfully",
+            TreasureDataError::JobTimeoutError(..) =>
+                "job didn't finish in time",
+            TreasureDataError::FromRowError(..) =>
+                "failed to convert a row of the result",
+            TreasureDataError::NotFoundError(..) =>
+                "resource not found",
+            TreasureDataError::NotEmptyError(..) =>
+                "resource not empty",
+            TreasureDataError::IoError(ref x) => x.description(),
+            TreasureDataError::RetryBudgetExhaustedError(..) =>
+                "retry budget ran out",
+            #[cfg(feature = "arrow")]
+            TreasureDataError::ArrowError(..) => "failed to build Arrow arrays"
         }
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn source(&self) -> Option<&(Error + 'static)> {
         match *self {
             TreasureDataError::JsonDecodeError(ref x) => Some(x),
             TreasureDataError::JsonParseError(ref x) => Some(x),
@@ -110,8 +196,19 @@ impl Error for TreasureDataError {
             TreasureDataError::TimeStampParseError(ref x) => Some(x),
             TreasureDataError::HttpError(ref x) => Some(x),
             TreasureDataError::ApiError(..) => None,
+            TreasureDataError::UnauthorizedError(..) => None,
+            TreasureDataError::DnsError(ref x) => Some(x),
             TreasureDataError::InvalidArgumentError(ref x) => Some(x),
-            TreasureDataError::IoError(ref x) => Some(x)
+            TreasureDataError::ChunkError(ref x) => Some(x),
+            TreasureDataError::JobFailedError(..) => None,
+            TreasureDataError::JobTimeoutError(..) => None,
+            TreasureDataError::FromRowError(..) => None,
+            TreasureDataError::NotFoundError(..) => None,
+            TreasureDataError::NotEmptyError(..) => None,
+            TreasureDataError::IoError(ref x) => Some(x),
+            TreasureDataError::RetryBudgetExhaustedError(ref x) => Some(x.as_ref()),
+            #[cfg(feature = "arrow")]
+            TreasureDataError::ArrowError(ref x) => Some(x)
         }
     }
 }
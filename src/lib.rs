@@ -1,12 +1,20 @@
+#[cfg(feature = "arrow")]
+extern crate arrow_array;
+#[cfg(feature = "arrow")]
+extern crate arrow_schema;
 extern crate chrono;
 extern crate flate2;
 extern crate reqwest;
 #[macro_use]
 extern crate log;
+extern crate md5;
+extern crate rand;
 extern crate regex;
 extern crate rmp;
 extern crate rmpv;
 extern crate rustc_serialize;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
 extern crate tempdir;
 
 pub mod error;
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::str::FromStr;
 use rustc_serialize::*;
 use chrono::*;
@@ -44,7 +45,63 @@ pub struct Table {
     pub estimated_storage_size: u64,
     pub last_import: Option<TimeStamp>,
     pub last_log_timestamp: Option<TimeStamp>,
-    pub expire_days: Option<u32>
+    pub expire_days: Option<u32>,
+    // `count` is refreshed asynchronously. These are `None` for responses lacking them
+    pub counter_updated_at: Option<TimeStamp>,
+    pub delete_protected: Option<bool>,
+    pub include_v: Option<bool>,
+    // Only item tables have these
+    pub primary_key: Option<String>,
+    pub primary_key_type: Option<String>
+}
+
+// Rows of a log table are partitioned by `time`, which item tables don't require
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableType {
+    Log,
+    Item
+}
+
+impl ToString for TableType {
+    fn to_string(&self) -> String {
+        match self {
+            &TableType::Log => "log".to_string(),
+            &TableType::Item => "item".to_string()
+        }
+    }
+}
+
+// Option of a table updated by `set_table_option()` with its value. `Other` takes the name
+// of an option not listed here as it's sent to the API
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableOption {
+    IncludeV(bool),
+    ExpireDays(u64),
+    PrimaryKey(String),
+    PrimaryKeyType(String),
+    Other(String, json::Json)
+}
+
+impl TableOption {
+    pub fn name(&self) -> &str {
+        match self {
+            &TableOption::IncludeV(_) => "include_v",
+            &TableOption::ExpireDays(_) => "expire_days",
+            &TableOption::PrimaryKey(_) => "primary_key",
+            &TableOption::PrimaryKeyType(_) => "primary_key_type",
+            &TableOption::Other(ref name, _) => name.as_str()
+        }
+    }
+
+    pub fn value(&self) -> json::Json {
+        match self {
+            &TableOption::IncludeV(x) => json::Json::Boolean(x),
+            &TableOption::ExpireDays(x) => json::Json::U64(x),
+            &TableOption::PrimaryKey(ref x) => json::Json::String(x.clone()),
+            &TableOption::PrimaryKeyType(ref x) => json::Json::String(x.clone()),
+            &TableOption::Other(_, ref x) => x.clone()
+        }
+    }
 }
 
 #[derive(Debug, RustcDecodable, RustcEncodable)]
@@ -67,6 +124,25 @@ pub struct Databases {
     pub databases: Vec<Database>
 }
 
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct User {
+    pub id: Option<u64>,
+    pub name: String,
+    pub email: String,
+    pub account_owner: Option<bool>,
+    pub created_at: Option<TimeStamp>
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct Users {
+    pub users: Vec<User>
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct ApiKeys {
+    pub apikeys: Vec<String>
+}
+
 #[derive(Debug)]
 pub enum JobStatus {
     Queued,
@@ -91,6 +167,66 @@ impl FromStr for JobStatus {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum BulkImportState {
+    Uploading,
+    Performing,
+    Ready,
+    Committing,
+    Committed
+}
+
+impl FromStr for BulkImportState {
+    type Err = json::DecoderError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uploading" => Ok(BulkImportState::Uploading),
+            "performing" => Ok(BulkImportState::Performing),
+            "ready" => Ok(BulkImportState::Ready),
+            "committing" => Ok(BulkImportState::Committing),
+            "committed" => Ok(BulkImportState::Committed),
+            _ => Err(json::DecoderError::ExpectedError(
+                    "(uploading|performing|ready|committing|committed)".to_string(),
+                    s.to_string()))
+        }
+    }
+}
+
+// Saved session of a data connector importing from an external source into
+// `database`.`table`. `delay` is in seconds after the scheduled time
+#[derive(Debug)]
+pub struct ConnectorSession {
+    pub name: String,
+    pub database: String,
+    pub table: String,
+    pub cron: Option<String>,
+    pub timezone: Option<String>,
+    pub delay: u64
+}
+
+impl ConnectorSession {
+    // Whether TD runs the session by its `cron`, rather than only when it's run explicitly
+    pub fn is_scheduled(&self) -> bool {
+        self.cron.is_some()
+    }
+}
+
+// Bulk import session. The record counts are set once `perform` has finished,
+// and should be checked before committing the session
+#[derive(Debug)]
+pub struct BulkImportStatus {
+    pub name: String,
+    pub database: String,
+    pub table: String,
+    pub state: BulkImportState,
+    pub upload_frozen: bool,
+    pub job_id: Option<u64>,
+    pub valid_records: Option<u64>,
+    pub error_records: Option<u64>,
+    pub valid_parts: Option<u64>,
+    pub error_parts: Option<u64>
+}
+
 #[derive(Debug, RustcEncodable)]
 pub enum JobQuery {
     Query(String),
@@ -116,6 +252,42 @@ pub struct Job {
     pub duration: Option<u64>
 }
 
+// Job as it was submitted. `result_url` is `None` unless the result was written out
+#[derive(Debug)]
+pub struct JobDetail {
+    pub job_id: u64,
+    pub database: String,
+    pub query: JobQuery,
+    pub result_url: Option<String>,
+    pub priority: u64,
+    pub status: JobStatus
+}
+
+#[derive(Debug)]
+pub struct JobDebug {
+    pub cmdout: Option<String>,
+    pub stderr: Option<String>
+}
+
+// Stats of a finished job reported by TD. `duration` is the elapsed time of the job
+// and `result_size` is in bytes
+#[derive(Debug)]
+pub struct ResultInfo {
+    pub num_records: Option<u64>,
+    pub result_size: Option<u64>,
+    pub duration: Option<Duration>,
+    pub cpu_time: Option<String>
+}
+
+// Estimated size of a scan. `bytes` prorates the storage size of the table
+// by the ratio of `rows` to `table_rows`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanEstimate {
+    pub rows: u64,
+    pub table_rows: u64,
+    pub bytes: u64
+}
+
 #[derive(Debug)]
 pub struct Jobs {
     pub count: u64,
@@ -156,7 +328,58 @@ impl FromStr for QueryType {
     }
 }
 
-#[derive(Debug, RustcDecodable, RustcEncodable)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultFormat {
+    Json,
+    Tsv,
+    Csv,
+    Msgpack,
+    MsgpackGz
+}
+
+impl ResultFormat {
+    // Only query jobs have `hive_result_schema` that textual formats are rendered from.
+    // The other job types can emit their result only as MessagePack
+    pub fn supported_for(job_type: &str) -> Vec<ResultFormat> {
+        match job_type {
+            "hive" | "presto" | "pig" =>
+                vec![ResultFormat::Json, ResultFormat::Tsv, ResultFormat::Csv,
+                     ResultFormat::Msgpack, ResultFormat::MsgpackGz],
+            _ => vec![ResultFormat::Msgpack, ResultFormat::MsgpackGz]
+        }
+    }
+}
+
+impl ToString for ResultFormat {
+    fn to_string(&self) -> String {
+        match self {
+            &ResultFormat::Json => "json".to_string(),
+            &ResultFormat::Tsv => "tsv".to_string(),
+            &ResultFormat::Csv => "csv".to_string(),
+            &ResultFormat::Msgpack => "msgpack".to_string(),
+            &ResultFormat::MsgpackGz => "msgpack.gz".to_string()
+        }
+    }
+}
+
+impl FromStr for ResultFormat {
+    type Err = InvalidArgument;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ResultFormat::Json),
+            "tsv" => Ok(ResultFormat::Tsv),
+            "csv" => Ok(ResultFormat::Csv),
+            "msgpack" => Ok(ResultFormat::Msgpack),
+            "msgpack.gz" => Ok(ResultFormat::MsgpackGz),
+            _ => Err(InvalidArgument {
+                key: "result_format".to_string(),
+                value: s.to_string()
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
 pub enum SchemaType {
     Int,
     Long,
@@ -180,3 +403,139 @@ impl ToString for SchemaType {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Administrator,
+    FullAccess,
+    ImportOnly,
+    QueryOnly
+}
+
+impl ToString for Permission {
+    fn to_string(&self) -> String {
+        match self {
+            &Permission::Administrator => "administrator".to_string(),
+            &Permission::FullAccess => "full_access".to_string(),
+            &Permission::ImportOnly => "import_only".to_string(),
+            &Permission::QueryOnly => "query_only".to_string()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AclResource {
+    Database(String),
+    Table(String, String)
+}
+
+impl AclResource {
+    // TD names consist of lower case letters, digits and '_' and are 3 to 255 characters long
+    fn validate_name(key: &str, name: &str) -> Result<(), InvalidArgument> {
+        let valid = name.len() >= 3 && name.len() <= 255 &&
+            name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if valid {
+            Ok(())
+        }
+        else {
+            Err(InvalidArgument {
+                key: key.to_string(),
+                value: name.to_string()
+            })
+        }
+    }
+
+    pub fn to_scope(&self) -> Result<String, InvalidArgument> {
+        match self {
+            &AclResource::Database(ref database_name) => {
+                try!(AclResource::validate_name("database_name", database_name));
+                Ok(format!("databases.{}", database_name))
+            },
+            &AclResource::Table(ref database_name, ref table_name) => {
+                try!(AclResource::validate_name("database_name", database_name));
+                try!(AclResource::validate_name("table_name", table_name));
+                Ok(format!("databases.{}.tables.{}", database_name, table_name))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultMode {
+    Append,
+    Replace,
+    Truncate,
+    Update
+}
+
+impl ToString for ResultMode {
+    fn to_string(&self) -> String {
+        match self {
+            &ResultMode::Append => "append".to_string(),
+            &ResultMode::Replace => "replace".to_string(),
+            &ResultMode::Truncate => "truncate".to_string(),
+            &ResultMode::Update => "update".to_string()
+        }
+    }
+}
+
+// Keep only unreserved characters of RFC 3986 as they are
+pub fn percent_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+fn percent_encode_path(path: &str) -> String {
+    path.split('/').map(percent_encode).collect::<Vec<String>>().join("/")
+}
+
+// URL to write a query result to. Credentials are percent-encoded, and masked in `Debug`
+// so that the URL can be logged safely. Use `as_str()` to get the actual URL
+#[derive(Clone, PartialEq)]
+pub struct ResultUrl {
+    url: String,
+    redacted: String
+}
+
+impl ResultUrl {
+    pub fn s3(bucket: &str, path: &str, access_key: &str, secret: &str) -> ResultUrl {
+        let location = format!("@/{}/{}", percent_encode(bucket),
+                               percent_encode_path(path.trim_start_matches('/')));
+        ResultUrl {
+            url: format!("s3://{}:{}{}", percent_encode(access_key), percent_encode(secret),
+                         location),
+            redacted: format!("s3://{}:****{}", percent_encode(access_key), location)
+        }
+    }
+
+    pub fn td(database_name: &str, table_name: &str, mode: ResultMode) -> ResultUrl {
+        let url = format!("td://@/{}/{}?mode={}", percent_encode(database_name),
+                          percent_encode(table_name), mode.to_string());
+        ResultUrl {
+            url: url.clone(),
+            redacted: url
+        }
+    }
+
+    pub fn mysql(user: &str, password: &str, host: &str, database_name: &str,
+                 table_name: &str, mode: ResultMode) -> ResultUrl {
+        let location = format!("@{}/{}/{}?mode={}", host, percent_encode(database_name),
+                               percent_encode(table_name), mode.to_string());
+        ResultUrl {
+            url: format!("mysql://{}:{}{}", percent_encode(user), percent_encode(password),
+                         location),
+            redacted: format!("mysql://{}:****{}", percent_encode(user), location)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.url.as_str()
+    }
+}
+
+impl fmt::Debug for ResultUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResultUrl({})", self.redacted)
+    }
+}
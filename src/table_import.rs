@@ -1,33 +1,483 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::io::ErrorKind;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use flate2::Compression;
+use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use rmp::encode::*;
+use rustc_serialize::json::Json;
 use tempdir::TempDir;
 
+use model::SchemaType;
+use value::*;
+
+// Summary of a sealed chunk from `TableImportReadableChunk::metadata()`.
+// `uncompressed_bytes` is the MessagePack before compression, and `md5` is the lowercase
+// hex MD5 of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkMetadata {
+    pub path: String,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub row_count: u64,
+    pub codec: ChunkCodec,
+    pub md5: String
+}
+
+// A chunk finished in memory by `TableImportWritableChunk::finish_bytes()`, with the stats
+// kept from writing like `ChunkMetadata`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkBytes {
+    pub bytes: Vec<u8>,
+    pub uncompressed_bytes: u64,
+    pub row_count: u64
+}
+
+// A destination of rows. Converters from other formats can be written against this
+// trait instead of a concrete chunk
+pub trait RowSink {
+    fn begin_row(&mut self, len: u32) -> Result<(), TableImportChunkError>;
+    fn write_field(&mut self, key: &str, value: &Value) -> Result<(), TableImportChunkError>;
+    fn end_row(&mut self) -> Result<(), TableImportChunkError>;
+}
+
+// A field value which can be written to a chunk by `write_row_dyn()`. `None` of an
+// `Option` is written as nil
+pub trait ToMsgpack {
+    fn write_to(&self, chunk: &mut TableImportWritableChunk, key: &str)
+                -> Result<(), TableImportChunkError>;
+}
+
+macro_rules! impl_to_msgpack {
+    ($($t:ty => |$chunk:ident, $key:ident, $val:ident| $body:expr;)*) => (
+        $(
+            impl ToMsgpack for $t {
+                fn write_to(&self, $chunk: &mut TableImportWritableChunk, $key: &str)
+                            -> Result<(), TableImportChunkError> {
+                    let $val = self;
+                    $body
+                }
+            }
+        )*
+    )
+}
+
+impl_to_msgpack! {
+    i32 => |chunk, key, val| chunk.write_key_and_i32(key, *val);
+    i64 => |chunk, key, val| chunk.write_key_and_i64(key, *val);
+    u32 => |chunk, key, val| chunk.write_key_and_u32(key, *val);
+    u64 => |chunk, key, val| chunk.write_key_and_u64(key, *val);
+    f64 => |chunk, key, val| chunk.write_key_and_f64(key, *val);
+    bool => |chunk, key, val| chunk.write_key_and_bool(key, *val);
+    String => |chunk, key, val| chunk.write_key_and_str(key, val);
+    Vec<u8> => |chunk, key, val| chunk.write_key_and_bin(key, val);
+    Value => |chunk, key, val| chunk.write_key_and_value(key, val);
+}
+
+impl<'a> ToMsgpack for &'a str {
+    fn write_to(&self, chunk: &mut TableImportWritableChunk, key: &str)
+                -> Result<(), TableImportChunkError> {
+        chunk.write_key_and_str(key, self)
+    }
+}
+
+impl<T: ToMsgpack> ToMsgpack for Option<T> {
+    fn write_to(&self, chunk: &mut TableImportWritableChunk, key: &str)
+                -> Result<(), TableImportChunkError> {
+        match *self {
+            Some(ref val) => val.write_to(chunk, key),
+            None => chunk.write_key_and_nil(key)
+        }
+    }
+}
+
+// How rows are laid out in a chunk. `Array` wraps all the rows in a top-level
+// array header, so the number of rows needs to be given up front
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FramingMode {
+    MapStream,
+    Array(u32)
+}
+
+// Capacity of the buffer between the encoder and the file. MessagePack tokens are
+// tiny, so writing them through without a buffer costs a syscall for each
+pub const DEFAULT_BUFFER_CAPACITY: usize = 64 * 1024;
+
+// Compression of a chunk, which decides the format to import it as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkCodec {
+    Gzip,
+    Uncompressed
+}
+
+impl ChunkCodec {
+    pub fn format(&self) -> &'static str {
+        match *self {
+            ChunkCodec::Gzip => "msgpack.gz",
+            ChunkCodec::Uncompressed => "msgpack"
+        }
+    }
+}
+
+// Where the compressed rows go. `Memory` is for callers who want the bytes
+// at hand, e.g. to use them as a request body directly
+enum ChunkOutput {
+    File(BufWriter<File>),
+    Memory(Vec<u8>)
+}
+
+impl Write for ChunkOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ChunkOutput::File(ref mut w) => w.write(buf),
+            ChunkOutput::Memory(ref mut w) => w.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ChunkOutput::File(ref mut w) => w.flush(),
+            ChunkOutput::Memory(ref mut w) => w.flush()
+        }
+    }
+}
+
+// Encoder of the chunk. `Raw` is for pipelines compressing the data by themselves.
+// `Row` holds a row started by `next_row_dynamic()` until its length is known
+enum ChunkEncoder {
+    Gzip(GzEncoder<ChunkOutput>),
+    Raw(ChunkOutput),
+    Row(Box<ChunkEncoder>, Vec<u8>)
+}
+
+impl ChunkEncoder {
+    fn try_finish(&mut self) -> io::Result<()> {
+        match *self {
+            ChunkEncoder::Gzip(ref mut w) => {
+                try!(w.try_finish());
+                w.get_mut().flush()
+            },
+            ChunkEncoder::Raw(ref mut w) => w.flush(),
+            ChunkEncoder::Row(ref mut w, _) => w.try_finish()
+        }
+    }
+
+    // A pending row is discarded
+    fn finish(self) -> io::Result<ChunkOutput> {
+        match self {
+            ChunkEncoder::Gzip(w) => w.finish(),
+            ChunkEncoder::Raw(w) => Ok(w),
+            ChunkEncoder::Row(w, _) => w.finish()
+        }
+    }
+}
+
+impl Write for ChunkEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ChunkEncoder::Gzip(ref mut w) => w.write(buf),
+            ChunkEncoder::Raw(ref mut w) => w.write(buf),
+            ChunkEncoder::Row(_, ref mut row) => row.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ChunkEncoder::Gzip(ref mut w) => w.flush(),
+            ChunkEncoder::Raw(ref mut w) => w.flush(),
+            ChunkEncoder::Row(..) => Ok(())
+        }
+    }
+}
+
+// Size of the scratch buffer of `ChunkWrite`
+const SCRATCH_CAPACITY: usize = 8192;
+
+// Counts the bytes given to the encoder, i.e. MessagePack before compression.
+// A pending row is counted when it's written out to the encoder.
+// `rmp::encode` writes a value by a few tiny writes, e.g. a marker and then the data,
+// each of which costs a call into the compressor. They're gathered in `scratch`,
+// which is reused through the chunk, and handed to the encoder by the block.
+// With `hold_rows`, the scratch keeps the current row whole until the next one starts,
+// so that a row crossing the size limit can be taken back
+struct ChunkWrite {
+    encoder: ChunkEncoder,
+    scratch: Vec<u8>,
+    bytes: u64,
+    hold_rows: bool
+}
+
+impl ChunkWrite {
+    fn new(output: ChunkOutput, codec: ChunkCodec) -> ChunkWrite {
+        let encoder = match codec {
+            ChunkCodec::Gzip => ChunkEncoder::Gzip(GzEncoder::new(output, Compression::Default)),
+            ChunkCodec::Uncompressed => ChunkEncoder::Raw(output)
+        };
+        ChunkWrite {
+            encoder: encoder,
+            scratch: Vec::with_capacity(SCRATCH_CAPACITY),
+            bytes: 0,
+            hold_rows: false
+        }
+    }
+
+    // Drops the bytes held for the current row
+    fn discard_held_row(&mut self) {
+        self.bytes -= self.scratch.len() as u64;
+        self.scratch.clear();
+    }
+
+    // Always goes to the encoder under a pending row, since the scratch holds
+    // the bytes before the row
+    fn drain_scratch(&mut self) -> io::Result<()> {
+        if self.scratch.is_empty() {
+            return Ok(())
+        }
+        try!(match self.encoder {
+            ChunkEncoder::Row(ref mut w, _) => w.write_all(&self.scratch),
+            ref mut w => w.write_all(&self.scratch)
+        });
+        self.scratch.clear();
+        Ok(())
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        try!(self.drain_scratch());
+        self.encoder.try_finish()
+    }
+
+    fn finish(mut self) -> io::Result<ChunkOutput> {
+        try!(self.drain_scratch());
+        self.encoder.finish()
+    }
+}
+
+impl Write for ChunkWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let ChunkEncoder::Row(..) = self.encoder {
+            return self.encoder.write(buf)
+        }
+        if self.hold_rows {
+            self.scratch.extend_from_slice(buf);
+            self.bytes += buf.len() as u64;
+            return Ok(buf.len())
+        }
+        if self.scratch.len() + buf.len() > SCRATCH_CAPACITY {
+            try!(self.drain_scratch());
+        }
+        if buf.len() >= SCRATCH_CAPACITY {
+            try!(self.encoder.write_all(buf));
+        }
+        else {
+            self.scratch.extend_from_slice(buf);
+        }
+        self.bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.drain_scratch());
+        self.encoder.flush()
+    }
+}
+
+// Column key encoded as a MessagePack string up front, so that writing it
+// on every row of a wide chunk only copies the bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedKey {
+    key: String,
+    bytes: Vec<u8>
+}
+
+impl EncodedKey {
+    pub fn new(key: &str) -> EncodedKey {
+        let mut bytes = Vec::with_capacity(key.len() + 5);
+        // Writing to a Vec doesn't fail
+        write_str(&mut bytes, key).unwrap();
+        EncodedKey {
+            key: key.to_string(),
+            bytes: bytes
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.key.as_str()
+    }
+}
+
+// Schema the rows are bound to by `with_schema()`
+struct DeclaredSchema {
+    columns: Vec<(String, SchemaType)>,
+    set_in_row: Vec<bool>
+}
+
 pub struct TableImportWritableChunk {
+    framing: FramingMode,
+    codec: ChunkCodec,
+    buffer_capacity: usize,
     elms_in_row: Option<(u32, u32)>,
+    keys_in_row: Option<Vec<String>>,
+    schema: Option<Vec<(String, SchemaType)>>,
+    column_counts: Option<HashMap<String, u64>>,
+    max_columns: Option<u32>,
+    max_bytes: Option<u64>,
+    write_row_count: bool,
+    assume_time_present: bool,
+    reject_text_as_bin: bool,
+    declared: Option<DeclaredSchema>,
+    on_row: Option<Box<FnMut(u64) + Send>>,
+    rows: u64,
+    finished: bool,
     file_path: String,
-    tmp_dir: TempDir,
-    write: GzEncoder<File>
+    tmp_dir: Option<TempDir>,
+    write: ChunkWrite
 }
 
 #[allow(dead_code)]
 pub struct TableImportReadableChunk {
     pub file_path: String,
+    schema: Option<Vec<(String, SchemaType)>>,
+    column_counts: Option<HashMap<String, u64>>,
+    rows: u64,
+    uncompressed_bytes: u64,
+    framing: FramingMode,
+    buffer_capacity: usize,
+    codec: ChunkCodec,
     tmp_dir: TempDir
 }
 
+fn meta_path(file_path: &str) -> String {
+    format!("{}.meta", file_path)
+}
+
+fn path_to_string(path: &Path) -> Result<String, TableImportChunkError> {
+    path.to_str().map(|x| x.to_string()).
+        ok_or(TableImportChunkError::PathError(path.to_path_buf()))
+}
+
+// Lowercase hex digest of everything `read` produces
+fn md5_hex_digest<R: Read>(mut read: R) -> io::Result<String> {
+    let mut context = ::md5::Context::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = try!(read.read(&mut buf));
+        if len == 0 {
+            break
+        }
+        context.consume(&buf[..len]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+// Reader keeping the bytes read through it, to copy values in their encoding
+struct RecordingReader<R: Read> {
+    inner: R,
+    recorded: Vec<u8>
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = try!(self.inner.read(buf));
+        self.recorded.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+// Length of a map or an array of `marker`, reading the big endian length after it if any
+fn marker_len<R: Read>(d: &mut R, marker: ::rmp::Marker, row: usize)
+                       -> Result<u32, TableImportChunkError> {
+    let mut buf = [0u8; 4];
+    let size = match marker {
+        ::rmp::Marker::FixMap(len) | ::rmp::Marker::FixArray(len) => return Ok(len as u32),
+        ::rmp::Marker::Map16 | ::rmp::Marker::Array16 => 2,
+        _ => 4
+    };
+    try!(d.read_exact(&mut buf[..size]).map_err(|err| TableImportChunkError::VerificationError(
+        format!("Failed to decode row {}: {}", row, err))));
+    Ok(buf[..size].iter().fold(0, |len, b| len << 8 | *b as u32))
+}
+
+fn fill_rates(column_counts: &Option<HashMap<String, u64>>, rows: u64) -> HashMap<String, f64> {
+    match *column_counts {
+        Some(ref counts) if rows > 0 =>
+            counts.iter().map(|(key, count)| (key.clone(), *count as f64 / rows as f64)).collect(),
+        _ => HashMap::new()
+    }
+}
+
+// Whether a value of `written` can be stored as `declared` without turning it into
+// a string, e.g. a long into a double column
+fn fits_schema_type(declared: &SchemaType, written: &SchemaType) -> bool {
+    match (declared, written) {
+        (&SchemaType::Array(ref x), &SchemaType::Array(ref y)) => fits_schema_type(x, y),
+        (&SchemaType::String, y) => *y == SchemaType::String,
+        (&SchemaType::Array(_), _) | (_, &SchemaType::Array(_)) |
+        (_, &SchemaType::String) => false,
+        (x, y) => widen_schema_type(x, y) == *x
+    }
+}
+
+// Integers are widened to floating points, and the other conflicts fall back to string
+fn widen_schema_type(a: &SchemaType, b: &SchemaType) -> SchemaType {
+    match (a, b) {
+        (x, y) if x == y => x.clone(),
+        (&SchemaType::Int, &SchemaType::Long) | (&SchemaType::Long, &SchemaType::Int) =>
+            SchemaType::Long,
+        (&SchemaType::Int, &SchemaType::Float) | (&SchemaType::Float, &SchemaType::Int) =>
+            SchemaType::Float,
+        (&SchemaType::Int, &SchemaType::Double) | (&SchemaType::Double, &SchemaType::Int) |
+        (&SchemaType::Long, &SchemaType::Float) | (&SchemaType::Float, &SchemaType::Long) |
+        (&SchemaType::Long, &SchemaType::Double) | (&SchemaType::Double, &SchemaType::Long) |
+        (&SchemaType::Float, &SchemaType::Double) | (&SchemaType::Double, &SchemaType::Float) =>
+            SchemaType::Double,
+        (&SchemaType::Array(ref x), &SchemaType::Array(ref y)) =>
+            SchemaType::Array(Box::new(widen_schema_type(x, y))),
+        _ => SchemaType::String
+    }
+}
+
+fn schema_type_of(value: &Value) -> Option<SchemaType> {
+    match *value {
+        Value::Integer(_) => Some(SchemaType::Long),
+        Value::Float(Float::F32(_)) => Some(SchemaType::Float),
+        Value::Float(Float::F64(_)) => Some(SchemaType::Double),
+        Value::String(_) => Some(SchemaType::String),
+        Value::Array(ref xs) => schema_type_of_array(xs),
+        _ => None
+    }
+}
+
+fn schema_type_of_array(xs: &[Value]) -> Option<SchemaType> {
+    xs.iter().
+        filter_map(schema_type_of).
+        fold(None, |acc: Option<SchemaType>, x| match acc {
+            Some(acc) => Some(widen_schema_type(&acc, &x)),
+            None => Some(x)
+        }).
+        map(|x| SchemaType::Array(Box::new(x)))
+}
+
 #[derive(Debug, Clone)]
-pub struct UnmatchElementNumsError(Option<(u32, u32)>);
+pub struct UnmatchElementNumsError(Option<(u32, u32)>, Option<Vec<String>>);
 
 impl fmt::Display for UnmatchElementNumsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.0 {
-            Some((capacity, added)) => write!(f, "capacity:{}, added:{}", capacity, added),
-            None => write!(f, "Not initialized yet")
+        match (&self.0, &self.1) {
+            (&Some((capacity, added)), &Some(ref keys)) =>
+                write!(f, "capacity:{}, added:{}, keys:[{}]", capacity, added, keys.join(", ")),
+            (&Some((capacity, added)), &None) =>
+                write!(f, "capacity:{}, added:{}", capacity, added),
+            (&None, _) => write!(f, "Not initialized yet")
         }
     }
 }
@@ -43,7 +493,36 @@ pub enum TableImportChunkError {
     IOError(io::Error),
     UnmatchElementNums(UnmatchElementNumsError),
     UnexpectedError(String),
-    MsgpackValueWriteError(ValueWriteError)
+    MsgpackValueWriteError(ValueWriteError),
+    VerificationError(String),
+    UnmatchRowNums(u32, u64),
+    InvalidFileName(String),
+    InvalidUuid(String),
+    InvalidStrStream(String),
+    ValueOutOfRange(String),
+    PathError(PathBuf),
+    AlreadyClosed,
+    TooManyColumns { len: u32, max: u32 },
+    ColumnWriteError(String, Box<TableImportChunkError>),
+    NotAnObject(String),
+    UnfinishedRow,
+    ChunkFull { bytes: u64, max: u64 },
+    InvalidJsonLine(usize, String),
+    TimeColumnPresent,
+    RowInProgress(UnmatchElementNumsError),
+    UndeclaredColumn(String),
+    SchemaMismatch(String, SchemaType, SchemaType),
+    TextAsBinary(String)
+}
+
+// Attaches the key being written to the error of `expr`
+macro_rules! keyed {
+    ($key:expr, $expr:expr) => (
+        try!(($expr).map_err(|err| {
+            TableImportChunkError::ColumnWriteError(
+                $key.to_string(), Box::new(TableImportChunkError::from(err)))
+        }))
+    )
 }
 
 impl From<UnmatchElementNumsError> for TableImportChunkError {
@@ -70,7 +549,40 @@ impl fmt::Display for TableImportChunkError {
             TableImportChunkError::IOError(ref x) => write!(f, "{}", x),
             TableImportChunkError::UnmatchElementNums(ref x) => write!(f, "{}", x),
             TableImportChunkError::UnexpectedError(ref x) => write!(f, "{}", x),
-            TableImportChunkError::MsgpackValueWriteError(ref x) => write!(f, "{}", x)
+            TableImportChunkError::MsgpackValueWriteError(ref x) => write!(f, "{}", x),
+            TableImportChunkError::VerificationError(ref x) => write!(f, "{}", x),
+            TableImportChunkError::UnmatchRowNums(capacity, added) =>
+                write!(f, "row capacity:{}, added:{}", capacity, added),
+            TableImportChunkError::InvalidFileName(ref x) => write!(f, "Invalid file name: {}", x),
+            TableImportChunkError::InvalidUuid(ref x) => write!(f, "Invalid UUID: {}", x),
+            TableImportChunkError::InvalidStrStream(ref x) => write!(f, "{}", x),
+            TableImportChunkError::ValueOutOfRange(ref x) => write!(f, "Value out of range: {}", x),
+            TableImportChunkError::PathError(ref x) =>
+                write!(f, "Failed to convert path to string: {:?}", x),
+            TableImportChunkError::AlreadyClosed => write!(f, "The chunk has been finished"),
+            TableImportChunkError::TooManyColumns { len, max } =>
+                write!(f, "Too many columns: {} (max: {})", len, max),
+            TableImportChunkError::ColumnWriteError(ref key, ref x) =>
+                write!(f, "while writing column \"{}\": {}", key, x),
+            TableImportChunkError::NotAnObject(ref x) =>
+                write!(f, "Expected a JSON object as a row: {}", x),
+            TableImportChunkError::UnfinishedRow =>
+                write!(f, "The row started by next_row_dynamic() isn't finished"),
+            TableImportChunkError::RowInProgress(ref x) =>
+                write!(f, "The chunk can't be sealed while a row is partially written ({})", x),
+            TableImportChunkError::ChunkFull { bytes, max } =>
+                write!(f, "The chunk is full: {} bytes (max: {})", bytes, max),
+            TableImportChunkError::InvalidJsonLine(line, ref x) =>
+                write!(f, "Invalid JSON at line {}: {}", line, x),
+            TableImportChunkError::TimeColumnPresent =>
+                write!(f, "The rows already have a time column"),
+            TableImportChunkError::UndeclaredColumn(ref x) =>
+                write!(f, "Column \"{}\" isn't declared in the schema", x),
+            TableImportChunkError::SchemaMismatch(ref key, ref declared, ref written) =>
+                write!(f, "Column \"{}\" is declared as {} but written as {}",
+                       key, declared.to_string(), written.to_string()),
+            TableImportChunkError::TextAsBinary(ref x) =>
+                write!(f, "Column \"{}\" is written as binary but is valid UTF-8", x)
         }
     }
 }
@@ -81,229 +593,3308 @@ impl Error for TableImportChunkError {
             TableImportChunkError::IOError(ref x) => x.description(),
             TableImportChunkError::UnmatchElementNums(ref x) => x.description(),
             TableImportChunkError::UnexpectedError(ref x) => x,
-            TableImportChunkError::MsgpackValueWriteError(ref x) => x.description()
+            TableImportChunkError::MsgpackValueWriteError(ref x) => x.description(),
+            TableImportChunkError::VerificationError(ref x) => x,
+            TableImportChunkError::UnmatchRowNums(..) =>
+                "The number of rows in the chunk is unexpected",
+            TableImportChunkError::InvalidFileName(..) =>
+                "The file name must not contain path separators",
+            TableImportChunkError::InvalidUuid(..) =>
+                "The UUID must be formatted as xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx",
+            TableImportChunkError::InvalidStrStream(..) =>
+                "The stream didn't produce the declared length of UTF-8 bytes",
+            TableImportChunkError::ValueOutOfRange(..) =>
+                "The value can't be represented by the MessagePack type",
+            TableImportChunkError::PathError(..) => "The path is not valid UTF-8",
+            TableImportChunkError::AlreadyClosed => "The chunk can't be written after finished",
+            TableImportChunkError::TooManyColumns { .. } =>
+                "The row has more columns than the limit",
+            TableImportChunkError::ColumnWriteError(_, ref x) => x.description(),
+            TableImportChunkError::NotAnObject(..) => "A row must be a JSON object",
+            TableImportChunkError::UnfinishedRow => "finish_row() needs to be called first",
+            TableImportChunkError::RowInProgress(..) => "The current row needs to be completed first",
+            TableImportChunkError::ChunkFull { .. } => "The chunk has reached the size limit",
+            TableImportChunkError::InvalidJsonLine(..) =>
+                "A line must be a JSON object",
+            TableImportChunkError::TimeColumnPresent =>
+                "A row must have exactly one time column",
+            TableImportChunkError::UndeclaredColumn(..) => "The column isn't in the schema",
+            TableImportChunkError::SchemaMismatch(..) =>
+                "The value doesn't match the type declared in the schema",
+            TableImportChunkError::TextAsBinary(..) => "Text must be written as a string"
+        }
+    }
+
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            TableImportChunkError::IOError(ref x) => Some(x),
+            TableImportChunkError::UnmatchElementNums(ref x) => Some(x),
+            TableImportChunkError::MsgpackValueWriteError(ref x) => Some(x),
+            TableImportChunkError::ColumnWriteError(_, ref x) => Some(x.as_ref()),
+            TableImportChunkError::UnexpectedError(..) |
+            TableImportChunkError::VerificationError(..) |
+            TableImportChunkError::UnmatchRowNums(..) |
+            TableImportChunkError::InvalidFileName(..) |
+            TableImportChunkError::InvalidUuid(..) |
+            TableImportChunkError::InvalidStrStream(..) |
+            TableImportChunkError::ValueOutOfRange(..) |
+            TableImportChunkError::PathError(..) |
+            TableImportChunkError::AlreadyClosed |
+            TableImportChunkError::TooManyColumns { .. } |
+            TableImportChunkError::NotAnObject(..) |
+            TableImportChunkError::UnfinishedRow |
+            TableImportChunkError::RowInProgress(..) |
+            TableImportChunkError::ChunkFull { .. } |
+            TableImportChunkError::InvalidJsonLine(..) |
+            TableImportChunkError::TimeColumnPresent |
+            TableImportChunkError::UndeclaredColumn(..) |
+            TableImportChunkError::SchemaMismatch(..) |
+            TableImportChunkError::TextAsBinary(..) => None
         }
     }
 }
 
+static CHUNK_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+// A file name not shared with any other chunk, so chunks created in the same second
+// don't collide even when their files are moved into one directory. It's made of the
+// process id, a per-process sequence and the current time instead of a random UUID
+fn unique_file_name(codec: ChunkCodec) -> String {
+    let sequence = CHUNK_SEQUENCE.fetch_add(1, Ordering::SeqCst);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).
+        map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64).
+        unwrap_or(0);
+    format!("{:08x}-{:08x}-{:016x}.{}", process::id(), sequence, nanos, codec.format())
+}
+
 impl TableImportWritableChunk {
     pub fn new() -> Result<TableImportWritableChunk, TableImportChunkError> {
-        // let uuid =  Uuid::new_v4().hyphenated().to_string();
-        // let tmp_dir = try!(TempDir::new(format!("td-client-rust-{}", uuid).as_str()));
-        let tmp_dir = try!(TempDir::new("td-client-rust"));
-        let tmp_file_path = tmp_dir.path().join("msgpack.gz");
-        let file_path = try!(tmp_file_path.
-                             to_str().
-                             ok_or(
-                                 TableImportChunkError::UnexpectedError(
-                                     format!("Failed to convert path to string: {:?}",
-                                             tmp_file_path))
-                                 )).to_string();
-        let file = try!(File::create(file_path.clone()));
-        let write = GzEncoder::new(file, Compression::Default);
-        Ok(TableImportWritableChunk {
-            elms_in_row: None,
-            file_path: file_path,
-            tmp_dir: tmp_dir,
-            write: write
-        })
+        TableImportWritableChunk::new_with_framing(FramingMode::MapStream)
     }
 
-    fn check_elm_number(&self) -> Result<(), TableImportChunkError> {
-        match self.elms_in_row {
-            Some((capacity, added)) =>
-                if capacity != added {
-                    try!(Err(UnmatchElementNumsError(Some((capacity, added)))))
-                },
-            None => ()
-        };
-        Ok(())
+    pub fn new_with_framing(framing: FramingMode)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        TableImportWritableChunk::create(unique_file_name(ChunkCodec::Gzip).as_str(), framing,
+                                         DEFAULT_BUFFER_CAPACITY, ChunkCodec::Gzip)
     }
 
-    pub fn next_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
-        try!(self.check_elm_number());
-        try!(write_map_len(&mut self.write, len));
-        self.elms_in_row = Some((len, 0));
-        Ok(())
+    pub fn new_with_buffer_capacity(capacity: usize)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        TableImportWritableChunk::create(unique_file_name(ChunkCodec::Gzip).as_str(),
+                                         FramingMode::MapStream, capacity, ChunkCodec::Gzip)
     }
 
-    fn incr_elms_in_row(&mut self) -> Result<(), UnmatchElementNumsError> {
-        match self.elms_in_row {
-            Some((capacity, added)) => {
-                let new_added = added + 1;
-                if capacity < new_added {
-                    try!(Err(UnmatchElementNumsError(Some((capacity, new_added)))))
-                }
-                else {
-                    self.elms_in_row = Some((capacity, new_added));
-                    Ok(())
-                }
-            },
-            None => try!(Err(UnmatchElementNumsError(None)))
+    // `ChunkCodec::Uncompressed` writes raw MessagePack, which is imported as `msgpack`
+    pub fn new_with_codec(codec: ChunkCodec)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        TableImportWritableChunk::create(unique_file_name(codec).as_str(), FramingMode::MapStream,
+                                         DEFAULT_BUFFER_CAPACITY, codec)
+    }
+
+    // For callers rotating chunks by themselves. The chunk never gets more than `max` bytes
+    // of MessagePack before compression: the write making a row cross it fails with
+    // `ChunkFull` and takes the whole row back, so the chunk can still be closed with
+    // the rows before it. The row taken back is still counted in `inferred_schema()`,
+    // `column_fill_rates()` and by the `on_row()` callback
+    pub fn new_with_max_bytes(max: u64)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        let mut chunk = try!(TableImportWritableChunk::new());
+        chunk.set_max_bytes(Some(max));
+        Ok(chunk)
+    }
+
+    fn set_max_bytes(&mut self, max: Option<u64>) {
+        self.max_bytes = max;
+        if max.is_some() {
+            self.write.hold_rows = true;
         }
     }
 
-    pub fn write_key_and_array_header(&mut self, key: &str, len: u32) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_array_len(&mut self.write, len));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Keep the row being written in memory until the next one starts, so that
+    // `abandon_row()` can take it back. Chunks by `new_with_max_bytes()` always do this
+    pub fn hold_rows(&mut self, enabled: bool) -> &Self {
+        self.write.hold_rows = enabled || self.max_bytes.is_some();
+        self
     }
 
-    pub fn write_key_and_bin(&mut self, key: &str, data: &[u8]) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_bin(&mut self.write, data));
-        try!(self.incr_elms_in_row());
+    // Takes back a row partially written, e.g. after one of its fields failed, so that
+    // the next row can be written and the chunk closed with the rows before it.
+    // Needs `hold_rows()` unless the row is started by `next_row_dynamic()`
+    pub fn abandon_row(&mut self) -> Result<(), TableImportChunkError> {
+        if let ChunkEncoder::Row(..) = self.write.encoder {
+            if let ChunkEncoder::Row(encoder, _) = ::std::mem::replace(
+                &mut self.write.encoder, ChunkEncoder::Raw(ChunkOutput::Memory(Vec::new()))) {
+                self.write.encoder = *encoder;
+            }
+        }
+        else if !self.row_in_progress() {
+            return Ok(())
+        }
+        else if self.write.hold_rows {
+            self.write.discard_held_row();
+        }
+        else {
+            return Err(TableImportChunkError::UnexpectedError(
+                    "The row can't be taken back unless hold_rows() is enabled".to_string()))
+        }
+        self.discard_row();
         Ok(())
     }
 
+    // Binds the rows to `schema`. Writing a column not in it fails with `UndeclaredColumn`,
+    // and a value not fitting the declared type with `SchemaMismatch`. For rows started
+    // by `next_row_dynamic()`, `finish_row()` writes nil for the columns not written
+    pub fn with_schema(schema: &[(&str, SchemaType)])
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        let mut chunk = try!(TableImportWritableChunk::new());
+        chunk.declare_schema(schema.iter().map(|&(ref key, ref schema_type)|
+            (key.to_string(), schema_type.clone())).collect());
+        Ok(chunk)
+    }
 
-    pub fn write_key_and_bool(&mut self, key: &str, val: bool) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_bool(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    fn declare_schema(&mut self, columns: Vec<(String, SchemaType)>) {
+        self.declared = Some(DeclaredSchema {
+            set_in_row: vec![false; columns.len()],
+            columns: columns
+        });
     }
 
-    pub fn write_key_and_ext_meta(&mut self, key: &str, len: u32, typeid: i8) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_ext_meta(&mut self.write, len, typeid));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // `name` is used as the file name in the temporary directory
+    pub fn new_named(name: &str) -> Result<TableImportWritableChunk, TableImportChunkError> {
+        if name.is_empty() || name == "." || name == ".." ||
+            name.contains('/') || name.contains(::std::path::MAIN_SEPARATOR) {
+            return Err(TableImportChunkError::InvalidFileName(name.to_string()))
+        }
+        TableImportWritableChunk::create(name, FramingMode::MapStream, DEFAULT_BUFFER_CAPACITY,
+                                         ChunkCodec::Gzip)
     }
 
-    pub fn write_key_and_f32(&mut self, key: &str, val: f32) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_f32(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    fn create(file_name: &str, framing: FramingMode, buffer_capacity: usize, codec: ChunkCodec)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        let tmp_dir = try!(TempDir::new("td-client-rust"));
+        let file_path = try!(path_to_string(tmp_dir.path().join(file_name).as_path()));
+        TableImportWritableChunk::open(file_path, tmp_dir, framing, buffer_capacity, codec)
     }
 
-    pub fn write_key_and_f64(&mut self, key: &str, val: f64) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_f64(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Keeps the chunk in memory instead of a temporary file. Get the result by
+    // `finish_bytes()` since there is no file to `close()` to
+    pub fn new_in_memory(framing: FramingMode)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        TableImportWritableChunk::with_output(ChunkOutput::Memory(Vec::new()), String::new(), None,
+                                              framing, DEFAULT_BUFFER_CAPACITY, ChunkCodec::Gzip)
     }
 
-    pub fn write_key_and_i16(&mut self, key: &str, val: i16) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_i16(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    fn open(file_path: String, tmp_dir: TempDir, framing: FramingMode, buffer_capacity: usize,
+            codec: ChunkCodec)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        let file = try!(File::create(file_path.clone()));
+        let output = ChunkOutput::File(BufWriter::with_capacity(buffer_capacity, file));
+        TableImportWritableChunk::with_output(output, file_path, Some(tmp_dir),
+                                              framing, buffer_capacity, codec)
     }
 
-    pub fn write_key_and_i32(&mut self, key: &str, val: i32) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_i32(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    fn with_output(output: ChunkOutput, file_path: String, tmp_dir: Option<TempDir>,
+                   framing: FramingMode, buffer_capacity: usize, codec: ChunkCodec)
+        -> Result<TableImportWritableChunk, TableImportChunkError> {
+        let mut write = ChunkWrite::new(output, codec);
+        if let FramingMode::Array(len) = framing {
+            try!(write_array_len(&mut write, len));
+        }
+        Ok(TableImportWritableChunk {
+            framing: framing,
+            codec: codec,
+            buffer_capacity: buffer_capacity,
+            elms_in_row: None,
+            keys_in_row: None,
+            schema: None,
+            column_counts: None,
+            max_columns: None,
+            max_bytes: None,
+            write_row_count: false,
+            assume_time_present: false,
+            reject_text_as_bin: false,
+            declared: None,
+            on_row: None,
+            rows: 0,
+            finished: false,
+            file_path: file_path,
+            tmp_dir: tmp_dir,
+            write: write
+        })
     }
 
-    pub fn write_key_and_i64(&mut self, key: &str, val: i64) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_i64(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Discards the rows written so far and starts a fresh chunk on the same file,
+    // which saves creating another temporary directory in rotation loops. The file is
+    // truncated, so a readable chunk returned for it before, e.g. by `close()` of the chunk
+    // this one was reset from, must be consumed or uploaded before calling this
+    pub fn reset(self) -> Result<TableImportWritableChunk, TableImportChunkError> {
+        let track_keys = self.keys_in_row.is_some();
+        let infer_schema = self.schema.is_some();
+        let track_column_stats = self.column_counts.is_some();
+        let declared = self.declared.map(|declared| declared.columns);
+        let on_row = self.on_row;
+        let hold_rows = self.write.hold_rows;
+        try!(TableImportWritableChunk::finish_write(self.write));
+        let mut chunk = match self.tmp_dir {
+            Some(tmp_dir) => try!(
+                TableImportWritableChunk::open(self.file_path, tmp_dir, self.framing,
+                                               self.buffer_capacity, self.codec)),
+            None => try!(TableImportWritableChunk::new_in_memory(self.framing))
+        };
+        chunk.track_keys(track_keys);
+        chunk.infer_schema(infer_schema);
+        chunk.track_column_stats(track_column_stats);
+        chunk.max_columns(self.max_columns);
+        chunk.set_max_bytes(self.max_bytes);
+        chunk.hold_rows(hold_rows);
+        chunk.write_row_count(self.write_row_count);
+        chunk.assume_time_present(self.assume_time_present);
+        chunk.reject_text_as_bin(self.reject_text_as_bin);
+        if let Some(columns) = declared {
+            chunk.declare_schema(columns);
+        }
+        chunk.on_row = on_row;
+        Ok(chunk)
     }
 
-    pub fn write_key_and_i8(&mut self, key: &str, val: i8) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_i8(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Flushes the gzip trailer and then the buffer, so that the file is complete
+    // once this returns
+    fn finish_write(write: ChunkWrite) -> io::Result<ChunkOutput> {
+        let mut output = try!(write.finish());
+        try!(output.flush());
+        Ok(output)
     }
 
-    pub fn write_key_and_map_len(&mut self, key: &str, len: u32) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_map_len(&mut self.write, len));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    pub fn rows(&self) -> u64 {
+        self.rows
     }
 
-    pub fn write_key_and_nfix(&mut self, key: &str, val: i8) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_nfix(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Bytes of MessagePack written so far, before compression
+    pub fn written_bytes(&self) -> u64 {
+        self.write.bytes
     }
 
-    pub fn write_key_and_nil(&mut self, key: &str) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_nil(&mut self.write));
-        try!(self.incr_elms_in_row());
+    // Completes the gzip stream in place so that the file can be read while the chunk
+    // is kept, e.g. for its stats. Any write after this fails with `AlreadyClosed`
+    // because it would go after the gzip trailer
+    pub fn flush_final(&mut self) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_no_row_in_progress());
+        try!(self.check_row_number(self.rows));
+        try!(self.write.try_finish());
+        self.finished = true;
         Ok(())
     }
 
-    pub fn write_key_and_pfix(&mut self, key: &str, val: u8) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_pfix(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    fn ensure_writable(&self) -> Result<(), TableImportChunkError> {
+        if self.finished {
+            Err(TableImportChunkError::AlreadyClosed)
+        }
+        else {
+            Ok(())
+        }
     }
 
-    pub fn write_key_and_sint(&mut self, key: &str, val: i64) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_sint(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Remember the keys written in the current row so that
+    // `UnmatchElementNumsError` can tell which column is missing or extra
+    pub fn track_keys(&mut self, enabled: bool) -> &Self {
+        self.keys_in_row = if enabled { Some(Vec::new()) } else { None };
+        self
     }
 
-    pub fn write_key_and_sint_eff(&mut self, key: &str, val: i64) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_sint(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Collect the types of written columns so that the table schema can be updated to
+    // match. Nil, binary, boolean and ext values and map headers don't tell a column type
+    pub fn infer_schema(&mut self, enabled: bool) -> &Self {
+        self.schema = if enabled { Some(Vec::new()) } else { None };
+        self
     }
 
-    pub fn write_key_and_str(&mut self, key: &str, data: &str) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_str(&mut self.write, data));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    pub fn inferred_schema(&self) -> Vec<(String, SchemaType)> {
+        self.schema.clone().unwrap_or(Vec::new())
     }
 
-    pub fn write_key_and_u16(&mut self, key: &str, val: u16) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_u16(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Reject rows declaring more columns than `max_columns` in `next_row()`, which likely
+    // means values are used as keys by mistake. `None` means unlimited, which is the default
+    pub fn max_columns(&mut self, max_columns: Option<u32>) -> &Self {
+        self.max_columns = max_columns;
+        self
     }
 
-    pub fn write_key_and_u32(&mut self, key: &str, val: u32) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_u32(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Make `close()` write the number of rows to a sidecar file next to the chunk,
+    // e.g. `msgpack.gz.meta`, so that `declared_rows()` can detect a truncated file
+    pub fn write_row_count(&mut self, enabled: bool) -> &Self {
+        self.write_row_count = enabled;
+        self
     }
 
-    pub fn write_key_and_u64(&mut self, key: &str, val: u64) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_u64(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // A row must have exactly one `time` column. Enable this when the source rows
+    // already have it, so that `next_row_with_time()` and `write_current_time()` fail
+    // with `TimeColumnPresent` instead of adding another one
+    pub fn assume_time_present(&mut self, enabled: bool) -> &Self {
+        self.assume_time_present = enabled;
+        self
     }
 
-    pub fn write_key_and_u8(&mut self, key: &str, val: u8) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_u8(&mut self.write, val));
-        try!(self.incr_elms_in_row());
-        Ok(())
+    // Make `write_key_and_bin()` fail with `TextAsBinary` for data that's valid UTF-8,
+    // to catch text written as binary by mistake. Off by default since binary data,
+    // e.g. a short hash, can happen to be valid UTF-8
+    pub fn reject_text_as_bin(&mut self, enabled: bool) -> &Self {
+        self.reject_text_as_bin = enabled;
+        self
     }
 
-    pub fn write_key_and_uint(&mut self, key: &str, val: u64) -> Result<(), TableImportChunkError> {
-        try!(write_str(&mut self.write, key));
-        try!(write_uint(&mut self.write, val));
-        try!(self.incr_elms_in_row());
+    // Called with the number of rows so far, e.g. to show progress. A row is counted once
+    // `next_row()` starts it, or once `finish_row()` finishes a dynamic one. The callback
+    // only gets the count, so it can't touch the chunk
+    pub fn on_row<F>(&mut self, callback: F) -> &Self where F: FnMut(u64) + Send + 'static {
+        self.on_row = Some(Box::new(callback));
+        self
+    }
+
+    fn notify_row(&mut self) {
+        if let Some(ref mut callback) = self.on_row {
+            callback(self.rows);
+        }
+    }
+
+    // Count the rows where each column has a non-nil value, for `column_fill_rates()`
+    pub fn track_column_stats(&mut self, enabled: bool) -> &Self {
+        self.column_counts = if enabled { Some(HashMap::new()) } else { None };
+        self
+    }
+
+    // Ratio of the rows having a non-nil value to all the rows, for each column written.
+    // Empty unless `track_column_stats()` is enabled
+    pub fn column_fill_rates(&self) -> HashMap<String, f64> {
+        fill_rates(&self.column_counts, self.rows)
+    }
+
+    fn infer_type(&mut self, key: &str, schema_type: SchemaType) {
+        if let Some(ref mut schema) = self.schema {
+            if let Some(column) = schema.iter_mut().find(|column| column.0 == key) {
+                column.1 = widen_schema_type(&column.1, &schema_type);
+                return
+            }
+            schema.push((key.to_string(), schema_type));
+        }
+    }
+
+    fn check_elm_number(&self) -> Result<(), TableImportChunkError> {
+        if let ChunkEncoder::Row(..) = self.write.encoder {
+            return Err(TableImportChunkError::UnfinishedRow)
+        }
+        match self.elms_in_row {
+            Some((capacity, added)) =>
+                if capacity != added {
+                    try!(Err(UnmatchElementNumsError(Some((capacity, added)),
+                                                     self.keys_in_row.clone())))
+                },
+            None => ()
+        };
         Ok(())
     }
 
-    pub fn close(self) -> Result<TableImportReadableChunk, TableImportChunkError> {
+    // Whether a row has been started and doesn't have all of its elements yet
+    pub fn row_in_progress(&self) -> bool {
+        if let ChunkEncoder::Row(..) = self.write.encoder {
+            return true
+        }
+        match self.elms_in_row {
+            Some((capacity, added)) => capacity != added,
+            None => false
+        }
+    }
+
+    // A chunk is never sealed in the middle of a row, which would make it undecodable
+    // or split a record across chunks. Everything sealing a chunk checks this first,
+    // and rotating writers check it before swapping the chunk out
+    fn ensure_no_row_in_progress(&self) -> Result<(), TableImportChunkError> {
+        if self.row_in_progress() {
+            Err(TableImportChunkError::RowInProgress(
+                    UnmatchElementNumsError(self.elms_in_row, self.keys_in_row.clone())))
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    fn check_row_number(&self, rows: u64) -> Result<(), TableImportChunkError> {
+        match self.framing {
+            FramingMode::Array(capacity) if capacity as u64 != rows =>
+                Err(TableImportChunkError::UnmatchRowNums(capacity, rows)),
+            _ => Ok(())
+        }
+    }
+
+    pub fn next_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_elm_number());
+        if let Some(max) = self.max_columns {
+            if len > max {
+                return Err(TableImportChunkError::TooManyColumns { len: len, max: max })
+            }
+        }
+        try!(self.check_row_capacity());
+        if self.write.hold_rows {
+            try!(self.write.drain_scratch());
+        }
+        try!(write_map_len(&mut self.write, len));
+        self.init_row(len);
+        try!(self.check_written_bytes());
+        self.notify_row();
+        Ok(())
+    }
+
+    fn check_row_capacity(&self) -> Result<(), TableImportChunkError> {
+        if let Some(max) = self.max_bytes {
+            if self.write.bytes >= max {
+                return Err(TableImportChunkError::ChunkFull { bytes: self.write.bytes, max: max })
+            }
+        }
+        match self.framing {
+            FramingMode::Array(capacity) if capacity as u64 <= self.rows =>
+                Err(TableImportChunkError::UnmatchRowNums(capacity, self.rows + 1)),
+            _ => Ok(())
+        }
+    }
+
+    // Takes back the current row once it gets over `max_bytes`. Only rows started by
+    // `next_row()` are held by `ChunkWrite`, and a dynamic row is checked by `finish_row()`
+    fn check_written_bytes(&mut self) -> Result<(), TableImportChunkError> {
+        let max = match self.max_bytes {
+            Some(max) if self.write.bytes > max => max,
+            _ => return Ok(())
+        };
+        let bytes = self.write.bytes;
+        self.write.discard_held_row();
+        self.discard_row();
+        Err(TableImportChunkError::ChunkFull { bytes: bytes, max: max })
+    }
+
+    fn discard_row(&mut self) {
+        if self.elms_in_row.is_some() {
+            self.rows -= 1;
+        }
+        self.elms_in_row = None;
+        if let Some(ref mut keys) = self.keys_in_row {
+            keys.clear();
+        }
+    }
+
+    fn init_row(&mut self, len: u32) {
+        self.elms_in_row = Some((len, 0));
+        if let Some(ref mut declared) = self.declared {
+            for set in declared.set_in_row.iter_mut() {
+                *set = false;
+            }
+        }
+        if let Some(ref mut keys) = self.keys_in_row {
+            keys.clear();
+        }
+        self.rows += 1;
+    }
+
+    // Starts a row of `len` columns besides `time`, and writes `time` first
+    pub fn next_row_with_time(&mut self, len: u32, time: i64) -> Result<(), TableImportChunkError> {
+        if self.assume_time_present {
+            return Err(TableImportChunkError::TimeColumnPresent)
+        }
+        let len = try!(len.checked_add(1).ok_or(
+            TableImportChunkError::ValueOutOfRange(format!("{} columns besides time", len))));
+        try!(self.next_row(len));
+        self.write_key_and_i64("time", time)
+    }
+
+    // Writes the current Unix time as `time` in the row counted for it
+    pub fn write_current_time(&mut self) -> Result<(), TableImportChunkError> {
+        if self.assume_time_present {
+            return Err(TableImportChunkError::TimeColumnPresent)
+        }
+        let now = try!(SystemTime::now().duration_since(UNIX_EPOCH).map_err(|err|
+            TableImportChunkError::UnexpectedError(err.to_string())));
+        self.write_key_and_i64("time", now.as_secs() as i64)
+    }
+
+    // Starts a row without its length. The fields are buffered until `finish_row()`,
+    // which writes the map header for the number of the fields before them.
+    // Up to `max_columns` fields can be written if it's set
+    pub fn next_row_dynamic(&mut self) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
         try!(self.check_elm_number());
-        try!(self.write.finish());
+        try!(self.check_row_capacity());
+        let encoder = ::std::mem::replace(&mut self.write.encoder,
+                                          ChunkEncoder::Raw(ChunkOutput::Memory(Vec::new())));
+        self.write.encoder = ChunkEncoder::Row(Box::new(encoder), Vec::new());
+        let capacity = self.max_columns.unwrap_or(u32::max_value());
+        self.init_row(capacity);
+        Ok(())
+    }
+
+    pub fn finish_row(&mut self) -> Result<(), TableImportChunkError> {
+        if let ChunkEncoder::Row(..) = self.write.encoder {
+            try!(self.fill_undeclared_nils());
+        }
+        let row = match ::std::mem::replace(
+            &mut self.write.encoder, ChunkEncoder::Raw(ChunkOutput::Memory(Vec::new()))) {
+            ChunkEncoder::Row(encoder, row) => {
+                self.write.encoder = *encoder;
+                row
+            },
+            encoder => {
+                self.write.encoder = encoder;
+                return Err(TableImportChunkError::UnexpectedError(
+                        "No row is started by next_row_dynamic()".to_string()))
+            }
+        };
+        let len = self.elms_in_row.map(|(_, added)| added).unwrap_or(0);
+        if let Some(max) = self.max_bytes {
+            let mut header = Vec::new();
+            try!(write_map_len(&mut header, len));
+            let bytes = self.write.bytes + header.len() as u64 + row.len() as u64;
+            if bytes > max {
+                self.discard_row();
+                return Err(TableImportChunkError::ChunkFull { bytes: bytes, max: max })
+            }
+        }
+        try!(write_map_len(&mut self.write, len));
+        try!(self.write.write_all(&row));
+        self.elms_in_row = Some((len, len));
+        self.notify_row();
+        Ok(())
+    }
+
+    fn fill_undeclared_nils(&mut self) -> Result<(), TableImportChunkError> {
+        let missing: Vec<String> = match self.declared {
+            Some(ref declared) => declared.columns.iter().zip(declared.set_in_row.iter()).
+                filter(|&(_, set)| !set).map(|(column, _)| column.0.clone()).collect(),
+            None => return Ok(())
+        };
+        for key in missing {
+            try!(self.write_key_and_nil(key.as_str()));
+        }
+        Ok(())
+    }
+
+    pub fn start_row(&mut self, len: u32) -> Result<RowWriter, TableImportChunkError> {
+        try!(self.next_row(len));
+        Ok(RowWriter { chunk: self })
+    }
+
+    // Runs before the field is written, so that a rejected field leaves nothing in the row
+    fn check_declared(&mut self, key: &str, schema_type: Option<&SchemaType>)
+                      -> Result<(), TableImportChunkError> {
+        let declared = match self.declared {
+            Some(ref mut declared) => declared,
+            None => return Ok(())
+        };
+        let i = try!(declared.columns.iter().position(|column| column.0 == key).
+                     ok_or(TableImportChunkError::UndeclaredColumn(key.to_string())));
+        if let Some(written_type) = schema_type {
+            if !fits_schema_type(&declared.columns[i].1, written_type) {
+                return Err(TableImportChunkError::SchemaMismatch(
+                        key.to_string(), declared.columns[i].1.clone(), written_type.clone()))
+            }
+        }
+        declared.set_in_row[i] = true;
+        Ok(())
+    }
+
+    fn incr_elms_in_row(&mut self, key: &str) -> Result<(), TableImportChunkError> {
+        if let Some(ref mut counts) = self.column_counts {
+            // Look up first so that the key is copied only for a new column
+            if let Some(count) = counts.get_mut(key) {
+                *count += 1;
+            }
+            else {
+                counts.insert(key.to_string(), 1);
+            }
+        }
+        try!(self.count_elm_in_row(key));
+        self.check_written_bytes()
+    }
+
+    fn incr_nil_elms_in_row(&mut self, key: &str) -> Result<(), TableImportChunkError> {
+        try!(self.count_elm_in_row(key));
+        self.check_written_bytes()
+    }
+
+    fn count_elm_in_row(&mut self, key: &str) -> Result<(), UnmatchElementNumsError> {
+        if let Some(ref mut keys) = self.keys_in_row {
+            keys.push(key.to_string());
+        }
+        match self.elms_in_row {
+            Some((capacity, added)) => {
+                let new_added = added + 1;
+                if capacity < new_added {
+                    try!(Err(UnmatchElementNumsError(Some((capacity, new_added)),
+                                                     self.keys_in_row.clone())))
+                }
+                else {
+                    self.elms_in_row = Some((capacity, new_added));
+                    Ok(())
+                }
+            },
+            None => try!(Err(UnmatchElementNumsError(None, None)))
+        }
+    }
+
+    pub fn write_key_and_array_header(&mut self, key: &str, len: u32) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_array_len(&mut self.write, len));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    // An array counts as one element of the row however many items it has. The same goes
+    // for `write_key_and_value_array()`, while the items after `write_key_and_array_header()`
+    // are written without a key and aren't counted either
+    pub fn write_key_and_bool_array(&mut self, key: &str, vals: &[bool])
+                                    -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_array_len(&mut self.write, vals.len() as u32));
+        for val in vals {
+            keyed!(key, write_bool(&mut self.write, *val));
+        }
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    pub fn write_key_and_value_array(&mut self, key: &str, values: &[Value])
+                                     -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        let schema_type = schema_type_of_array(values);
+        try!(self.check_declared(key, schema_type.as_ref()));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_array_len(&mut self.write, values.len() as u32));
+        for value in values {
+            keyed!(key, self.write_value(value));
+        }
+        try!(self.incr_elms_in_row(key));
+        if let Some(schema_type) = schema_type {
+            self.infer_type(key, schema_type);
+        }
+        Ok(())
+    }
+
+    // TD stores binary columns as they are and doesn't index them as text.
+    // Use `write_key_and_str_lossy()` for text of unknown encoding
+    pub fn write_key_and_bin(&mut self, key: &str, data: &[u8]) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        if self.reject_text_as_bin && ::std::str::from_utf8(data).is_ok() {
+            return Err(TableImportChunkError::TextAsBinary(key.to_string()))
+        }
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_bin(&mut self.write, data));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+
+    // Written as a 16 bytes binary, which is more compact than the string form
+    pub fn write_key_and_uuid(&mut self, key: &str, uuid: &[u8; 16]) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        self.write_key_and_bin(key, uuid)
+    }
+
+    pub fn write_key_and_uuid_str(&mut self, key: &str, uuid: &str) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        let bytes = try!(parse_uuid(uuid));
+        self.write_key_and_uuid(key, &bytes)
+    }
+
+    pub fn write_key_and_bool(&mut self, key: &str, val: bool) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_bool(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    pub fn write_key_and_char(&mut self, key: &str, val: char) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        let mut buf = [0u8; 4];
+        self.write_key_and_str(key, val.encode_utf8(&mut buf))
+    }
+
+    // Writes only the header of an ext value, and there's no way to write its `len` bytes
+    // after it. Use `write_key_and_ext()` unless `len` is 0
+    pub fn write_key_and_ext_meta(&mut self, key: &str, len: u32, typeid: i8) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_ext_meta(&mut self.write, len, typeid));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    pub fn write_key_and_ext(&mut self, key: &str, typeid: i8, data: &[u8])
+                             -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        if data.len() > u32::max_value() as usize {
+            return Err(TableImportChunkError::ValueOutOfRange(
+                    format!("ext data of {} bytes", data.len())))
+        }
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_ext_meta(&mut self.write, data.len() as u32, typeid));
+        keyed!(key, self.write.write_all(data));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    // Fluentd's EventTime, which is the ext type 0 holding seconds and nanoseconds
+    // as big endian 32 bit integers
+    pub fn write_key_and_event_time(&mut self, key: &str, secs: u32, nanos: u32)
+                                    -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        let payload = match Value::event_time(secs, nanos) {
+            Some(Value::Ext(_, payload)) => payload,
+            _ => return Err(TableImportChunkError::ValueOutOfRange(
+                    format!("nanoseconds {} for key {}", nanos, key)))
+        };
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_ext_meta(&mut self.write, 8, 0));
+        keyed!(key, self.write.write_all(&payload));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    // Unix time in seconds like `time`. Use `Value::timestamp()` for a timestamp
+    // inside a nested map, which is encoded the same way
+    pub fn write_key_and_timestamp<Tz>(&mut self, key: &str, time: &::chrono::DateTime<Tz>)
+                                       -> Result<(), TableImportChunkError>
+        where Tz: ::chrono::TimeZone {
+        self.write_key_and_i64(key, time.timestamp())
+    }
+
+    pub fn write_key_and_f32(&mut self, key: &str, val: f32) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Float)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_f32(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Float);
+        Ok(())
+    }
+
+    pub fn write_key_and_f64(&mut self, key: &str, val: f64) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Double)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_f64(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Double);
+        Ok(())
+    }
+
+    // Writes a whole row of doubles, e.g. of metrics. Unless keys, stats or a schema are
+    // tracked, the fields are written in one loop without the bookkeeping of each field
+    pub fn write_f64_row(&mut self, keys: &[&str], values: &[f64])
+                         -> Result<(), TableImportChunkError> {
+        if keys.len() != values.len() {
+            return Err(TableImportChunkError::UnmatchElementNums(
+                    UnmatchElementNumsError(Some((keys.len() as u32, values.len() as u32)), None)))
+        }
+        try!(self.next_row(keys.len() as u32));
+        if self.keys_in_row.is_some() || self.schema.is_some() || self.column_counts.is_some() ||
+            self.declared.is_some() {
+            for (key, value) in keys.iter().zip(values.iter()) {
+                try!(self.write_key_and_f64(key, *value));
+            }
+            return Ok(())
+        }
+        for (key, value) in keys.iter().zip(values.iter()) {
+            keyed!(key, write_str(&mut self.write, key));
+            keyed!(key, write_f64(&mut self.write, *value));
+        }
+        self.elms_in_row = Some((keys.len() as u32, keys.len() as u32));
+        Ok(())
+    }
+
+    // Writes a whole row of fields of different types, e.g.
+    // `chunk.write_row_dyn(&[("time", &1470000000i64), ("name", &"foo")])`
+    pub fn write_row_dyn(&mut self, fields: &[(&str, &dyn ToMsgpack)])
+                         -> Result<(), TableImportChunkError> {
+        try!(self.next_row(fields.len() as u32));
+        for &(key, value) in fields {
+            try!(value.write_to(self, key));
+        }
+        Ok(())
+    }
+
+    // Written as a map `{"lat": lat, "lon": lon}`, which counts as one element of the row
+    pub fn write_key_and_geopoint(&mut self, key: &str, lat: f64, lon: f64)
+                                  -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(check_geopoint(key, lat, lon));
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_map_len(&mut self.write, 2));
+        keyed!(key, write_str(&mut self.write, "lat"));
+        keyed!(key, write_f64(&mut self.write, lat));
+        keyed!(key, write_str(&mut self.write, "lon"));
+        keyed!(key, write_f64(&mut self.write, lon));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    pub fn write_key_and_i16(&mut self, key: &str, val: i16) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Int)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_i16(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Int);
+        Ok(())
+    }
+
+    pub fn write_key_and_i32(&mut self, key: &str, val: i32) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Int)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_i32(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Int);
+        Ok(())
+    }
+
+    pub fn write_key_and_i64(&mut self, key: &str, val: i64) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Long)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_i64(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Long);
+        Ok(())
+    }
+
+    pub fn write_key_and_i8(&mut self, key: &str, val: i8) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Int)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_i8(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Int);
+        Ok(())
+    }
+
+    pub fn write_key_and_isize(&mut self, key: &str, val: isize) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        if val as i64 as isize != val {
+            return Err(TableImportChunkError::ValueOutOfRange(format!("{}: {}", key, val)))
+        }
+        self.write_key_and_sint(key, val as i64)
+    }
+
+    pub fn write_key_and_map_len(&mut self, key: &str, len: u32) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_map_len(&mut self.write, len));
+        try!(self.incr_elms_in_row(key));
+        Ok(())
+    }
+
+    // Negative fixint covers -32..=-1
+    pub fn write_key_and_nfix(&mut self, key: &str, val: i8) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        if val < -32 || val > -1 {
+            return Err(TableImportChunkError::ValueOutOfRange(format!("{}: {}", key, val)))
+        }
+        try!(self.check_declared(key, Some(&SchemaType::Int)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_nfix(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Int);
+        Ok(())
+    }
+
+    pub fn write_key_and_nil(&mut self, key: &str) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, None));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_nil(&mut self.write));
+        try!(self.incr_nil_elms_in_row(key));
+        Ok(())
+    }
+
+    // Positive fixint covers 0..=127
+    pub fn write_key_and_pfix(&mut self, key: &str, val: u8) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        if val > 127 {
+            return Err(TableImportChunkError::ValueOutOfRange(format!("{}: {}", key, val)))
+        }
+        try!(self.check_declared(key, Some(&SchemaType::Int)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_pfix(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Int);
+        Ok(())
+    }
+
+    pub fn write_key_and_sint(&mut self, key: &str, val: i64) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Long)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_sint(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Long);
+        Ok(())
+    }
+
+    pub fn write_key_and_sint_eff(&mut self, key: &str, val: i64) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Long)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_sint(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Long);
+        Ok(())
+    }
+
+    pub fn write_key_and_str(&mut self, key: &str, data: &str) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::String)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_str(&mut self.write, data));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::String);
+        Ok(())
+    }
+
+    // Writes `value` as a compact JSON string rather than a nested map, for columns
+    // parsed later in queries, e.g. with `json_extract()`
+    pub fn write_key_and_json(&mut self, key: &str, value: &Json) -> Result<(), TableImportChunkError> {
+        self.write_key_and_str(key, value.to_string().as_str())
+    }
+
+    // Invalid UTF-8 sequences in `data` are replaced with U+FFFD instead of failing,
+    // which suits importing dirty log data
+    pub fn write_key_and_str_lossy(&mut self, key: &str, data: &[u8])
+                                   -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        self.write_key_and_str(key, String::from_utf8_lossy(data).as_ref())
+    }
+
+    // Copies `byte_len` bytes of UTF-8 text from `reader` without loading it at once.
+    // The header is already written when an error is found, so the chunk should be discarded then
+    pub fn write_key_and_str_stream(&mut self, key: &str, reader: &mut impl Read, byte_len: u32)
+        -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::String)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_str_len(&mut self.write, byte_len));
+
+        let mut reader = reader.take(byte_len as u64);
+        let mut buf: [u8; 8192] = [0; 8192];
+        // The head of `buf` can keep an incomplete UTF-8 sequence from the previous read
+        let mut pending_len = 0;
+        let mut total_read_len: u64 = 0;
+        loop {
+            let read_len = try!(reader.read(&mut buf[pending_len..]));
+            if read_len == 0 {
+                break;
+            }
+            total_read_len += read_len as u64;
+            let filled_len = pending_len + read_len;
+            let valid_len = match ::std::str::from_utf8(&buf[..filled_len]) {
+                Ok(_) => filled_len,
+                Err(err) => match err.error_len() {
+                    Some(_) => return Err(TableImportChunkError::InvalidStrStream(
+                            format!("Invalid UTF-8 sequence at byte {}",
+                                    total_read_len - filled_len as u64 + err.valid_up_to() as u64))),
+                    None => err.valid_up_to()
+                }
+            };
+            keyed!(key, self.write.write_all(&buf[..valid_len]));
+            for i in valid_len..filled_len {
+                buf[i - valid_len] = buf[i];
+            }
+            pending_len = filled_len - valid_len;
+        }
+        if total_read_len != byte_len as u64 {
+            return Err(TableImportChunkError::InvalidStrStream(
+                    format!("byte_len:{}, read:{}", byte_len, total_read_len)))
+        }
+        if pending_len > 0 {
+            return Err(TableImportChunkError::InvalidStrStream(
+                    "Incomplete UTF-8 sequence at the end".to_string()))
+        }
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::String);
+        Ok(())
+    }
+
+    pub fn write_key_and_u16(&mut self, key: &str, val: u16) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Int)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_u16(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Int);
+        Ok(())
+    }
+
+    pub fn write_key_and_u32(&mut self, key: &str, val: u32) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Long)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_u32(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Long);
+        Ok(())
+    }
+
+    pub fn write_key_and_u64(&mut self, key: &str, val: u64) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Long)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_u64(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Long);
+        Ok(())
+    }
+
+    pub fn write_key_and_u8(&mut self, key: &str, val: u8) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Int)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_u8(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Int);
+        Ok(())
+    }
+
+    pub fn write_key_and_uint(&mut self, key: &str, val: u64) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key, Some(&SchemaType::Long)));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, write_uint(&mut self.write, val));
+        try!(self.incr_elms_in_row(key));
+        self.infer_type(key, SchemaType::Long);
+        Ok(())
+    }
+
+    fn write_value(&mut self, value: &Value) -> Result<(), TableImportChunkError> {
+        match *value {
+            Value::Nil => try!(write_nil(&mut self.write)),
+            Value::Boolean(x) => try!(write_bool(&mut self.write, x)),
+            Value::Integer(Integer::U64(x)) => { try!(write_uint(&mut self.write, x)); },
+            Value::Integer(Integer::I64(x)) => { try!(write_sint(&mut self.write, x)); },
+            Value::Float(Float::F32(x)) => try!(write_f32(&mut self.write, x)),
+            Value::Float(Float::F64(x)) => try!(write_f64(&mut self.write, x)),
+            Value::String(ref x) => try!(write_str(&mut self.write, x)),
+            Value::Binary(ref x) => try!(write_bin(&mut self.write, x)),
+            Value::Array(ref xs) => {
+                try!(write_array_len(&mut self.write, xs.len() as u32));
+                for x in xs {
+                    try!(self.write_value(x));
+                }
+            },
+            Value::Map(ref xs) => {
+                try!(write_map_len(&mut self.write, xs.len() as u32));
+                for &(ref k, ref v) in xs {
+                    try!(self.write_value(k));
+                    try!(self.write_value(v));
+                }
+            },
+            Value::Ext(typeid, ref data) => {
+                try!(write_ext_meta(&mut self.write, data.len() as u32, typeid));
+                try!(self.write.write_all(data));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_key_and_usize(&mut self, key: &str, val: usize) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        if val as u64 as usize != val {
+            return Err(TableImportChunkError::ValueOutOfRange(format!("{}: {}", key, val)))
+        }
+        self.write_key_and_uint(key, val as u64)
+    }
+
+    // The `write_encoded_key_and_*` functions are the same as `write_key_and_*`
+    // except that they copy the key encoded by `EncodedKey`
+    pub fn write_encoded_key_and_i64(&mut self, key: &EncodedKey, val: i64)
+                                     -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key.as_str(), Some(&SchemaType::Long)));
+        keyed!(key.as_str(), self.write.write_all(&key.bytes));
+        keyed!(key.as_str(), write_i64(&mut self.write, val));
+        try!(self.incr_elms_in_row(key.as_str()));
+        self.infer_type(key.as_str(), SchemaType::Long);
+        Ok(())
+    }
+
+    pub fn write_encoded_key_and_f64(&mut self, key: &EncodedKey, val: f64)
+                                     -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key.as_str(), Some(&SchemaType::Double)));
+        keyed!(key.as_str(), self.write.write_all(&key.bytes));
+        keyed!(key.as_str(), write_f64(&mut self.write, val));
+        try!(self.incr_elms_in_row(key.as_str()));
+        self.infer_type(key.as_str(), SchemaType::Double);
+        Ok(())
+    }
+
+    pub fn write_encoded_key_and_str(&mut self, key: &EncodedKey, data: &str)
+                                     -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        try!(self.check_declared(key.as_str(), Some(&SchemaType::String)));
+        keyed!(key.as_str(), self.write.write_all(&key.bytes));
+        keyed!(key.as_str(), write_str(&mut self.write, data));
+        try!(self.incr_elms_in_row(key.as_str()));
+        self.infer_type(key.as_str(), SchemaType::String);
+        Ok(())
+    }
+
+    pub fn write_encoded_key_and_value(&mut self, key: &EncodedKey, value: &Value)
+                                       -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        let schema_type = schema_type_of(value);
+        try!(self.check_declared(key.as_str(), schema_type.as_ref()));
+        keyed!(key.as_str(), self.write.write_all(&key.bytes));
+        keyed!(key.as_str(), self.write_value(value));
+        match *value {
+            Value::Nil => try!(self.incr_nil_elms_in_row(key.as_str())),
+            _ => try!(self.incr_elms_in_row(key.as_str()))
+        }
+        if let Some(schema_type) = schema_type {
+            self.infer_type(key.as_str(), schema_type);
+        }
+        Ok(())
+    }
+
+    pub fn write_key_and_value(&mut self, key: &str, value: &Value) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        let schema_type = schema_type_of(value);
+        try!(self.check_declared(key, schema_type.as_ref()));
+        keyed!(key, write_str(&mut self.write, key));
+        keyed!(key, self.write_value(value));
+        match *value {
+            Value::Nil => try!(self.incr_nil_elms_in_row(key)),
+            _ => try!(self.incr_elms_in_row(key))
+        }
+        if let Some(schema_type) = schema_type {
+            self.infer_type(key, schema_type);
+        }
+        Ok(())
+    }
+
+    // Copies the rows of `src` into this chunk, e.g. to merge small chunks into a larger
+    // part. The keys and values are copied byte for byte in their original encoding.
+    // Returns the number of the rows copied
+    pub fn extend_from_chunk(&mut self, src: &TableImportReadableChunk)
+                             -> Result<usize, TableImportChunkError> {
+        let mut d = RecordingReader { inner: try!(src.open_decoded()), recorded: Vec::new() };
+        let mut copied = 0;
+        loop {
+            let marker = match ::rmp::decode::read_marker(&mut d) {
+                Ok(marker) => marker,
+                Err(::rmp::decode::MarkerReadError(ref err))
+                    if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(::rmp::decode::MarkerReadError(err)) =>
+                    return Err(TableImportChunkError::VerificationError(
+                            format!("Failed to decode row {}: {}", copied, err)))
+            };
+            match marker {
+                ::rmp::Marker::FixMap(_) | ::rmp::Marker::Map16 | ::rmp::Marker::Map32 => {
+                    let len = try!(marker_len(&mut d, marker, copied));
+                    try!(self.copy_row(&mut d, len, copied));
+                    copied += 1;
+                },
+                ::rmp::Marker::FixArray(_) | ::rmp::Marker::Array16 | ::rmp::Marker::Array32 => {
+                    for _ in 0..try!(marker_len(&mut d, marker, copied)) {
+                        let len = try!(::rmp::decode::read_map_len(&mut d).map_err(|err|
+                            TableImportChunkError::VerificationError(
+                                format!("Unexpected value in row {}: {}", copied, err))));
+                        try!(self.copy_row(&mut d, len, copied));
+                        copied += 1;
+                    }
+                },
+                unexpected => return Err(TableImportChunkError::VerificationError(
+                        format!("Unexpected value in row {}: {:?}", copied, unexpected)))
+            }
+        }
+        try!(self.check_elm_number());
+        Ok(copied)
+    }
+
+    fn copy_row<R: Read>(&mut self, d: &mut RecordingReader<R>, len: u32, row: usize)
+                         -> Result<(), TableImportChunkError> {
+        let decode_error = |err: ::rmpv::decode::Error| TableImportChunkError::VerificationError(
+            format!("Failed to decode row {}: {}", row, err));
+        try!(self.next_row(len));
+        for _ in 0..len {
+            d.recorded.clear();
+            let key = match try!(::rmpv::decode::read_value(d).map_err(&decode_error)) {
+                ::rmpv::Value::String(key) => try!(key.into_str().ok_or(
+                    TableImportChunkError::VerificationError("Key isn't UTF-8".to_string()))),
+                unexpected => return Err(TableImportChunkError::VerificationError(
+                        format!("Unexpected key: {:?}", unexpected)))
+            };
+            let raw_key = ::std::mem::replace(&mut d.recorded, Vec::new());
+            let value = Value::from(try!(::rmpv::decode::read_value(d).map_err(&decode_error)));
+            try!(self.write_raw_key_and_value(key.as_str(), &raw_key, &value, &d.recorded));
+        }
+        Ok(())
+    }
+
+    // Same as `write_key_and_value()` except that the key and the value are copied
+    // as they're encoded in `raw_key` and `raw_value`
+    fn write_raw_key_and_value(&mut self, key: &str, raw_key: &[u8], value: &Value,
+                               raw_value: &[u8]) -> Result<(), TableImportChunkError> {
+        try!(self.ensure_writable());
+        let schema_type = schema_type_of(value);
+        try!(self.check_declared(key, schema_type.as_ref()));
+        keyed!(key, self.write.write_all(raw_key));
+        keyed!(key, self.write.write_all(raw_value));
+        match *value {
+            Value::Nil => try!(self.incr_nil_elms_in_row(key)),
+            _ => try!(self.incr_elms_in_row(key))
+        }
+        if let Some(schema_type) = schema_type {
+            self.infer_type(key, schema_type);
+        }
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<TableImportReadableChunk, TableImportChunkError> {
+        self.close_with_sync(false)
+    }
+
+    // Same as `close()`, but also syncs the file, and the row count file if any, to the
+    // disk before returning, so that the chunk survives a crash or a power loss once
+    // this returns. Syncing waits for the disk and can take far longer than writing
+    // the chunk, so it's only worth it for chunks staged for another process
+    pub fn close_durable(self) -> Result<TableImportReadableChunk, TableImportChunkError> {
+        self.close_with_sync(true)
+    }
+
+    fn close_with_sync(self, sync: bool) -> Result<TableImportReadableChunk, TableImportChunkError> {
+        try!(self.ensure_no_row_in_progress());
+        try!(self.check_row_number(self.rows));
+        let tmp_dir = try!(self.tmp_dir.ok_or(TableImportChunkError::UnexpectedError(
+                    "An in-memory chunk has no file. Use finish_bytes() instead".to_string())));
+        let uncompressed_bytes = self.write.bytes;
+        if let ChunkOutput::File(file) = try!(TableImportWritableChunk::finish_write(self.write)) {
+            if sync {
+                try!(file.get_ref().sync_all());
+            }
+        }
+        if self.write_row_count {
+            let mut meta = try!(File::create(meta_path(self.file_path.as_str())));
+            try!(write!(meta, "{}", self.rows));
+            if sync {
+                try!(meta.sync_all());
+            }
+        }
         Ok(TableImportReadableChunk {
             file_path: self.file_path,
-            tmp_dir: self.tmp_dir
+            schema: self.schema,
+            column_counts: self.column_counts,
+            rows: self.rows,
+            uncompressed_bytes: uncompressed_bytes,
+            framing: self.framing,
+            buffer_capacity: self.buffer_capacity,
+            codec: self.codec,
+            tmp_dir: tmp_dir
+        })
+    }
+
+    // Returns the gzipped MessagePack as bytes, reading the file back unless
+    // the chunk is in memory
+    pub fn finish_bytes(self) -> Result<ChunkBytes, TableImportChunkError> {
+        try!(self.ensure_no_row_in_progress());
+        try!(self.check_row_number(self.rows));
+        let uncompressed_bytes = self.write.bytes;
+        let bytes = match try!(TableImportWritableChunk::finish_write(self.write)) {
+            ChunkOutput::Memory(bytes) => bytes,
+            ChunkOutput::File(_) => {
+                let mut bytes = Vec::new();
+                try!(try!(File::open(self.file_path.as_str())).read_to_end(&mut bytes));
+                bytes
+            }
+        };
+        Ok(ChunkBytes {
+            bytes: bytes,
+            uncompressed_bytes: uncompressed_bytes,
+            row_count: self.rows
+        })
+    }
+
+    // Same as `close()`, but reads the written file back to make sure
+    // all the rows can be decoded. It costs another pass over the file
+    pub fn close_verified(self) -> Result<TableImportReadableChunk, TableImportChunkError> {
+        let rows = self.rows;
+        let chunk = try!(self.close());
+        try!(chunk.verify(chunk.framing, rows));
+        Ok(chunk)
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl TableImportWritableChunk {
+    // Writes each JSON object as a row, e.g. lines of JSON Lines parsed one by one.
+    // Nested values are kept as they are. Any other top-level value is rejected
+    // with `NotAnObject`, leaving the rows before it written
+    pub fn write_json_values<I>(&mut self, rows: I) -> Result<(), TableImportChunkError>
+        where I: Iterator<Item = ::serde_json::Value> {
+        for row in rows {
+            let columns = match row {
+                ::serde_json::Value::Object(columns) => columns,
+                other => return Err(TableImportChunkError::NotAnObject(other.to_string()))
+            };
+            try!(self.next_row(columns.len() as u32));
+            for (key, value) in columns {
+                try!(self.write_key_and_value(key.as_str(), &Value::from(value)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_uuid(uuid: &str) -> Result<[u8; 16], TableImportChunkError> {
+    let invalid_uuid = || TableImportChunkError::InvalidUuid(uuid.to_string());
+    let groups: Vec<&str> = uuid.split('-').collect();
+    if groups.iter().map(|g| g.len()).collect::<Vec<usize>>() != vec![8, 4, 4, 4, 12] {
+        return Err(invalid_uuid())
+    }
+    let hex = groups.concat();
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid_uuid())
+    }
+    let mut bytes = [0u8; 16];
+    for i in 0..16 {
+        bytes[i] = try!(u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid_uuid()));
+    }
+    Ok(bytes)
+}
+
+fn check_geopoint(key: &str, lat: f64, lon: f64) -> Result<(), TableImportChunkError> {
+    if !(lat >= -90.0 && lat <= 90.0) || !(lon >= -180.0 && lon <= 180.0) {
+        return Err(TableImportChunkError::ValueOutOfRange(
+                format!("geopoint ({}, {}) for key {}", lat, lon, key)))
+    }
+    Ok(())
+}
+
+fn check_fixint(key: &str, val: i64, min: i64, max: i64) -> Result<(), TableImportChunkError> {
+    if val < min || val > max {
+        return Err(TableImportChunkError::ValueOutOfRange(format!("{}: {}", key, val)))
+    }
+    Ok(())
+}
+
+// Writer of a row borrowing the chunk. While it's alive, the chunk can't be closed
+// and the next row can't be started, which the flat API checks only at runtime
+pub struct RowWriter<'a> {
+    chunk: &'a mut TableImportWritableChunk
+}
+
+macro_rules! delegate_row_writes {
+    ($field:ident; $($name:ident($($arg:ident: $arg_type:ty),*);)*) => (
+        $(
+            pub fn $name(&mut self, key: &str $(, $arg: $arg_type)*)
+                -> Result<(), TableImportChunkError> {
+                self.$field.$name(key $(, $arg)*)
+            }
+        )*
+    )
+}
+
+// Expands `delegate_row_writes!` with all the `write_key_and_*` methods of a chunk
+macro_rules! delegate_all_row_writes {
+    ($field:ident) => (
+        delegate_row_writes! {
+            $field;
+            write_key_and_array_header(len: u32);
+            write_key_and_bin(data: &[u8]);
+            write_key_and_bool(val: bool);
+            write_key_and_bool_array(vals: &[bool]);
+            write_key_and_char(val: char);
+            write_key_and_event_time(secs: u32, nanos: u32);
+            write_key_and_ext(typeid: i8, data: &[u8]);
+            write_key_and_ext_meta(len: u32, typeid: i8);
+            write_key_and_f32(val: f32);
+            write_key_and_f64(val: f64);
+            write_key_and_geopoint(lat: f64, lon: f64);
+            write_key_and_i16(val: i16);
+            write_key_and_i32(val: i32);
+            write_key_and_i64(val: i64);
+            write_key_and_i8(val: i8);
+            write_key_and_isize(val: isize);
+            write_key_and_json(value: &Json);
+            write_key_and_map_len(len: u32);
+            write_key_and_nfix(val: i8);
+            write_key_and_nil();
+            write_key_and_pfix(val: u8);
+            write_key_and_sint(val: i64);
+            write_key_and_sint_eff(val: i64);
+            write_key_and_str(data: &str);
+            write_key_and_str_lossy(data: &[u8]);
+            write_key_and_u16(val: u16);
+            write_key_and_u32(val: u32);
+            write_key_and_u64(val: u64);
+            write_key_and_u8(val: u8);
+            write_key_and_uint(val: u64);
+            write_key_and_usize(val: usize);
+            write_key_and_uuid(uuid: &[u8; 16]);
+            write_key_and_uuid_str(uuid: &str);
+            write_key_and_value(value: &Value);
+            write_key_and_value_array(values: &[Value]);
+        }
+    )
+}
+
+impl<'a> RowWriter<'a> {
+    delegate_all_row_writes!(chunk);
+
+    pub fn write_key_and_str_stream(&mut self, key: &str, reader: &mut impl Read, byte_len: u32)
+        -> Result<(), TableImportChunkError> {
+        self.chunk.write_key_and_str_stream(key, reader, byte_len)
+    }
+
+    pub fn finish_row(self) -> Result<(), TableImportChunkError> {
+        self.chunk.check_elm_number()
+    }
+}
+
+impl RowSink for TableImportWritableChunk {
+    fn begin_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
+        self.next_row(len)
+    }
+
+    fn write_field(&mut self, key: &str, value: &Value) -> Result<(), TableImportChunkError> {
+        self.write_key_and_value(key, value)
+    }
+
+    fn end_row(&mut self) -> Result<(), TableImportChunkError> {
+        self.check_elm_number()
+    }
+}
+
+// Writer rotating chunks every `rows_per_chunk` rows. A new chunk is started only
+// when the next row begins, so a row is never split across chunks
+pub struct MultiChunkWriter {
+    rows_per_chunk: u64,
+    current: TableImportWritableChunk,
+    ready: Vec<TableImportReadableChunk>
+}
+
+impl MultiChunkWriter {
+    pub fn new(rows_per_chunk: u64) -> Result<MultiChunkWriter, TableImportChunkError> {
+        if rows_per_chunk == 0 {
+            return Err(TableImportChunkError::ValueOutOfRange(
+                    "rows_per_chunk must be positive".to_string()))
+        }
+        Ok(MultiChunkWriter {
+            rows_per_chunk: rows_per_chunk,
+            current: try!(TableImportWritableChunk::new()),
+            ready: Vec::new()
+        })
+    }
+
+    pub fn next_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
+        if self.current.rows >= self.rows_per_chunk {
+            // Completes the stream before swapping, so that the chunk stays current on failure
+            try!(self.current.flush_final());
+            let sealed = ::std::mem::replace(&mut self.current,
+                                             try!(TableImportWritableChunk::new()));
+            self.ready.push(try!(sealed.close()));
+        }
+        self.current.next_row(len)
+    }
+
+    delegate_all_row_writes!(current);
+
+    pub fn write_key_and_str_stream(&mut self, key: &str, reader: &mut impl Read, byte_len: u32)
+        -> Result<(), TableImportChunkError> {
+        self.current.write_key_and_str_stream(key, reader, byte_len)
+    }
+
+    // Takes the chunks sealed so far, e.g. to upload them while writing the rest
+    pub fn drain_ready(&mut self) -> Vec<TableImportReadableChunk> {
+        ::std::mem::replace(&mut self.ready, Vec::new())
+    }
+
+    // Seals the current chunk unless it's empty and returns all the chunks not drained yet
+    pub fn finish(mut self) -> Result<Vec<TableImportReadableChunk>, TableImportChunkError> {
+        if self.current.rows > 0 {
+            self.ready.push(try!(self.current.close()));
+        }
+        Ok(self.ready)
+    }
+}
+
+impl RowSink for MultiChunkWriter {
+    fn begin_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
+        self.next_row(len)
+    }
+
+    fn write_field(&mut self, key: &str, value: &Value) -> Result<(), TableImportChunkError> {
+        self.write_key_and_value(key, value)
+    }
+
+    fn end_row(&mut self) -> Result<(), TableImportChunkError> {
+        self.current.check_elm_number()
+    }
+}
+
+// Defines `write_key_and_*` methods of a `RowSink`, each writing its arguments
+// as one field of the `Value` built by the expression
+macro_rules! sink_row_writes {
+    ($key:ident; $($name:ident($($arg:ident: $arg_type:ty),*) => $value:expr;)*) => (
+        $(
+            pub fn $name(&mut self, $key: &str $(, $arg: $arg_type)*)
+                -> Result<(), TableImportChunkError> {
+                let value = $value;
+                self.write_field($key, &value)
+            }
+        )*
+    )
+}
+
+// Sink writing every row to two sinks, e.g. a chunk to upload and an in-memory copy
+// kept for audit. A call goes to `second` only if `first` succeeded, so the sinks
+// may differ after an error. The fields are passed to the sinks as values, so
+// `write_key_and_array_header()`, `write_key_and_map_len()` and `write_key_and_ext_meta()`,
+// which write only a header, aren't provided
+pub struct TeeChunk<A: RowSink, B: RowSink> {
+    first: A,
+    second: B
+}
+
+impl<A: RowSink, B: RowSink> TeeChunk<A, B> {
+    pub fn new(first: A, second: B) -> TeeChunk<A, B> {
+        TeeChunk {
+            first: first,
+            second: second
+        }
+    }
+
+    pub fn next_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
+        self.begin_row(len)
+    }
+
+    sink_row_writes! {
+        key;
+        write_key_and_bin(data: &[u8]) => Value::Binary(data.to_vec());
+        write_key_and_bool(val: bool) => Value::Boolean(val);
+        write_key_and_bool_array(vals: &[bool]) =>
+            Value::Array(vals.iter().map(|val| Value::Boolean(*val)).collect());
+        write_key_and_char(val: char) => Value::String(val.to_string());
+        write_key_and_event_time(secs: u32, nanos: u32) =>
+            try!(Value::event_time(secs, nanos).ok_or_else(||
+                TableImportChunkError::ValueOutOfRange(
+                    format!("nanoseconds {} for key {}", nanos, key))));
+        write_key_and_ext(typeid: i8, data: &[u8]) => Value::Ext(typeid, data.to_vec());
+        write_key_and_f32(val: f32) => Value::Float(Float::F32(val));
+        write_key_and_f64(val: f64) => Value::Float(Float::F64(val));
+        write_key_and_geopoint(lat: f64, lon: f64) => {
+            try!(check_geopoint(key, lat, lon));
+            Value::Map(vec![(Value::String("lat".to_string()), Value::Float(Float::F64(lat))),
+                            (Value::String("lon".to_string()), Value::Float(Float::F64(lon)))])
+        };
+        write_key_and_i16(val: i16) => Value::Integer(Integer::I64(val as i64));
+        write_key_and_i32(val: i32) => Value::Integer(Integer::I64(val as i64));
+        write_key_and_i64(val: i64) => Value::Integer(Integer::I64(val));
+        write_key_and_i8(val: i8) => Value::Integer(Integer::I64(val as i64));
+        write_key_and_isize(val: isize) => Value::Integer(Integer::I64(val as i64));
+        write_key_and_json(value: &Json) => Value::String(value.to_string());
+        write_key_and_nfix(val: i8) => {
+            try!(check_fixint(key, val as i64, -32, -1));
+            Value::Integer(Integer::I64(val as i64))
+        };
+        write_key_and_nil() => Value::Nil;
+        write_key_and_pfix(val: u8) => {
+            try!(check_fixint(key, val as i64, 0, 127));
+            Value::Integer(Integer::U64(val as u64))
+        };
+        write_key_and_sint(val: i64) => Value::Integer(Integer::I64(val));
+        write_key_and_sint_eff(val: i64) => Value::Integer(Integer::I64(val));
+        write_key_and_str(data: &str) => Value::String(data.to_string());
+        write_key_and_str_lossy(data: &[u8]) =>
+            Value::String(String::from_utf8_lossy(data).into_owned());
+        write_key_and_u16(val: u16) => Value::Integer(Integer::U64(val as u64));
+        write_key_and_u32(val: u32) => Value::Integer(Integer::U64(val as u64));
+        write_key_and_u64(val: u64) => Value::Integer(Integer::U64(val));
+        write_key_and_u8(val: u8) => Value::Integer(Integer::U64(val as u64));
+        write_key_and_uint(val: u64) => Value::Integer(Integer::U64(val));
+        write_key_and_usize(val: usize) => Value::Integer(Integer::U64(val as u64));
+        write_key_and_uuid(uuid: &[u8; 16]) => Value::Binary(uuid.to_vec());
+        write_key_and_uuid_str(uuid: &str) => Value::Binary(try!(parse_uuid(uuid)).to_vec());
+        write_key_and_value(value: &Value) => value.clone();
+        write_key_and_value_array(values: &[Value]) => Value::Array(values.to_vec());
+    }
+
+    // Reads the whole text before writing it, unlike the chunk's one
+    pub fn write_key_and_str_stream(&mut self, key: &str, reader: &mut impl Read, byte_len: u32)
+        -> Result<(), TableImportChunkError> {
+        let mut data = String::new();
+        try!(reader.take(byte_len as u64).read_to_string(&mut data).map_err(|err|
+            TableImportChunkError::InvalidStrStream(err.to_string())));
+        if data.len() != byte_len as usize {
+            return Err(TableImportChunkError::InvalidStrStream(
+                    format!("byte_len:{}, read:{}", byte_len, data.len())))
+        }
+        self.write_field(key, &Value::String(data))
+    }
+
+    pub fn write_key_and_timestamp<Tz>(&mut self, key: &str, time: &::chrono::DateTime<Tz>)
+                                       -> Result<(), TableImportChunkError>
+        where Tz: ::chrono::TimeZone {
+        self.write_key_and_i64(key, time.timestamp())
+    }
+
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A: RowSink, B: RowSink> RowSink for TeeChunk<A, B> {
+    fn begin_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
+        try!(self.first.begin_row(len));
+        self.second.begin_row(len)
+    }
+
+    fn write_field(&mut self, key: &str, value: &Value) -> Result<(), TableImportChunkError> {
+        try!(self.first.write_field(key, value));
+        self.second.write_field(key, value)
+    }
+
+    fn end_row(&mut self) -> Result<(), TableImportChunkError> {
+        try!(self.first.end_row());
+        self.second.end_row()
+    }
+}
+
+// A call made to a `MockSink`
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkCall {
+    BeginRow(u32),
+    WriteField(String, Value),
+    EndRow
+}
+
+// In-memory `RowSink` recording every call, for testing code that builds rows without
+// a chunk. Like a chunk, `end_row()` fails if the row doesn't have the fields given to
+// `begin_row()`. Available in tests of this crate and with the `testing` feature
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Default)]
+pub struct MockSink {
+    calls: Vec<SinkCall>,
+    rows: Vec<Vec<(String, Value)>>,
+    current: Option<(u32, Vec<(String, Value)>)>
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockSink {
+    pub fn new() -> MockSink {
+        MockSink::default()
+    }
+
+    pub fn calls(&self) -> &[SinkCall] {
+        &self.calls
+    }
+
+    // Fields of the rows ended so far
+    pub fn rows(&self) -> &[Vec<(String, Value)>] {
+        &self.rows
+    }
+
+    // Panics unless the `index`th ended row has exactly `expected` fields in this order
+    pub fn assert_row(&self, index: usize, expected: &[(&str, Value)]) {
+        let row = match self.rows.get(index) {
+            Some(row) => row,
+            None => panic!("row {} doesn't exist, only {} rows were written",
+                           index, self.rows.len())
+        };
+        let expected: Vec<(String, Value)> = expected.iter().
+            map(|&(ref key, ref value)| (key.to_string(), value.clone())).collect();
+        assert_eq!(&expected, row, "fields of row {} differ", index);
+    }
+
+    pub fn assert_row_count(&self, expected: usize) {
+        assert_eq!(expected, self.rows.len(), "number of rows differs");
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl RowSink for MockSink {
+    fn begin_row(&mut self, len: u32) -> Result<(), TableImportChunkError> {
+        self.calls.push(SinkCall::BeginRow(len));
+        if self.current.is_some() {
+            return Err(TableImportChunkError::UnfinishedRow)
+        }
+        self.current = Some((len, Vec::new()));
+        Ok(())
+    }
+
+    fn write_field(&mut self, key: &str, value: &Value) -> Result<(), TableImportChunkError> {
+        self.calls.push(SinkCall::WriteField(key.to_string(), value.clone()));
+        match self.current {
+            Some((_, ref mut fields)) => fields.push((key.to_string(), value.clone())),
+            None => return Err(TableImportChunkError::from(UnmatchElementNumsError(None, None)))
+        }
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> Result<(), TableImportChunkError> {
+        self.calls.push(SinkCall::EndRow);
+        let (len, fields) = match self.current.take() {
+            Some(current) => current,
+            None => return Err(TableImportChunkError::from(UnmatchElementNumsError(None, None)))
+        };
+        if len as usize != fields.len() {
+            let keys = fields.iter().map(|&(ref key, _)| key.clone()).collect();
+            return Err(TableImportChunkError::from(
+                    UnmatchElementNumsError(Some((len, fields.len() as u32)), Some(keys))))
+        }
+        self.rows.push(fields);
+        Ok(())
+    }
+}
+
+// Collector of chunks written by many threads, shared e.g. in an `Arc`. Each thread
+// writes to its own `MultiChunkWriter` from `writer()` without locking, and hands the
+// sealed chunks over with `hand_over()` or `finish_writer()`. The uploader takes them
+// with `take_ready()`, so chunks of different threads come in no particular order
+pub struct SharedChunkSink {
+    rows_per_chunk: u64,
+    ready: Mutex<Vec<TableImportReadableChunk>>
+}
+
+impl SharedChunkSink {
+    pub fn new(rows_per_chunk: u64) -> Result<SharedChunkSink, TableImportChunkError> {
+        if rows_per_chunk == 0 {
+            return Err(TableImportChunkError::ValueOutOfRange(
+                    "rows_per_chunk must be positive".to_string()))
+        }
+        Ok(SharedChunkSink {
+            rows_per_chunk: rows_per_chunk,
+            ready: Mutex::new(Vec::new())
+        })
+    }
+
+    pub fn writer(&self) -> Result<MultiChunkWriter, TableImportChunkError> {
+        MultiChunkWriter::new(self.rows_per_chunk)
+    }
+
+    // Takes the chunks the writer has sealed so far
+    pub fn hand_over(&self, writer: &mut MultiChunkWriter) {
+        self.push(writer.drain_ready());
+    }
+
+    pub fn finish_writer(&self, writer: MultiChunkWriter) -> Result<(), TableImportChunkError> {
+        self.push(try!(writer.finish()));
+        Ok(())
+    }
+
+    pub fn take_ready(&self) -> Vec<TableImportReadableChunk> {
+        ::std::mem::replace(&mut *self.lock_ready(), Vec::new())
+    }
+
+    fn push(&self, chunks: Vec<TableImportReadableChunk>) {
+        self.lock_ready().extend(chunks);
+    }
+
+    // A thread panicking while holding the lock can't leave the list half updated,
+    // so a poisoned lock is used as it is
+    fn lock_ready(&self) -> ::std::sync::MutexGuard<Vec<TableImportReadableChunk>> {
+        match self.ready.lock() {
+            Ok(ready) => ready,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+pub type Record = Vec<(String, Value)>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkOptions {
+    pub rows_per_chunk: u64
+}
+
+struct ChunksFrom<I: Iterator<Item = Record>> {
+    records: I,
+    opts: ChunkOptions,
+    finished: bool
+}
+
+impl<I: Iterator<Item = Record>> ChunksFrom<I> {
+    fn next_chunk(&mut self) -> Result<Option<TableImportReadableChunk>, TableImportChunkError> {
+        if self.opts.rows_per_chunk == 0 {
+            return Err(TableImportChunkError::ValueOutOfRange(
+                    "rows_per_chunk must be positive".to_string()))
+        }
+        let mut chunk = try!(TableImportWritableChunk::new());
+        while chunk.rows < self.opts.rows_per_chunk {
+            match self.records.next() {
+                Some(record) => {
+                    try!(chunk.next_row(record.len() as u32));
+                    for &(ref key, ref value) in record.iter() {
+                        try!(chunk.write_key_and_value(key.as_str(), value));
+                    }
+                },
+                None => {
+                    self.finished = true;
+                    break
+                }
+            }
+        }
+        if chunk.rows == 0 {
+            Ok(None)
+        }
+        else {
+            Ok(Some(try!(chunk.close())))
+        }
+    }
+}
+
+impl<I: Iterator<Item = Record>> Iterator for ChunksFrom<I> {
+    type Item = Result<TableImportReadableChunk, TableImportChunkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None
+        }
+        match self.next_chunk() {
+            Ok(chunk) => chunk.map(Ok),
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+// Packs `records` into chunks of `opts.rows_per_chunk` rows lazily. The last chunk
+// may have fewer rows, and iteration stops after the first error
+pub fn chunks_from<I>(records: I, opts: ChunkOptions)
+    -> impl Iterator<Item = Result<TableImportReadableChunk, TableImportChunkError>>
+    where I: Iterator<Item = Record> {
+    ChunksFrom {
+        records: records,
+        opts: opts,
+        finished: false
+    }
+}
+
+// Writes each line of newline-delimited JSON as a row and returns the number of rows.
+// Blank lines are skipped. A line that isn't a JSON object fails the import with
+// `InvalidJsonLine` telling its 1-based line number, leaving the rows before it written
+pub fn import_ndjson<R: Read>(reader: R, writer: &mut TableImportWritableChunk)
+    -> Result<usize, TableImportChunkError> {
+    let mut rows = 0;
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let columns = match Json::from_str(line.as_str()) {
+            Ok(Json::Object(columns)) => columns,
+            Ok(other) => return Err(TableImportChunkError::InvalidJsonLine(
+                    i + 1, format!("not an object: {}", other))),
+            Err(err) => return Err(TableImportChunkError::InvalidJsonLine(i + 1, err.to_string()))
+        };
+        try!(writer.next_row(columns.len() as u32));
+        for (key, value) in columns {
+            try!(writer.write_key_and_value(key.as_str(), &Value::from(value)));
+        }
+        rows += 1;
+    }
+    Ok(rows)
+}
+
+impl TableImportReadableChunk {
+    pub fn inferred_schema(&self) -> Vec<(String, SchemaType)> {
+        self.schema.clone().unwrap_or(Vec::new())
+    }
+
+    pub fn column_fill_rates(&self) -> HashMap<String, f64> {
+        fill_rates(&self.column_counts, self.rows)
+    }
+
+    pub fn codec(&self) -> ChunkCodec {
+        self.codec
+    }
+
+    // `compressed_bytes` and `md5` are taken from the file, which is read through for `md5`.
+    // The others are kept from writing
+    pub fn metadata(&self) -> io::Result<ChunkMetadata> {
+        let compressed_bytes = try!(::std::fs::metadata(self.file_path.as_str())).len();
+        let md5 = try!(md5_hex_digest(try!(File::open(self.file_path.as_str()))));
+        Ok(ChunkMetadata {
+            path: self.file_path.clone(),
+            compressed_bytes: compressed_bytes,
+            uncompressed_bytes: self.uncompressed_bytes,
+            row_count: self.rows,
+            codec: self.codec,
+            md5: md5
+        })
+    }
+
+    // Whether any row has `key`, which is known only if `infer_schema()` or
+    // `track_column_stats()` was enabled on the writable chunk
+    pub fn has_column(&self, key: &str) -> Option<bool> {
+        if self.schema.is_none() && self.column_counts.is_none() {
+            return None
+        }
+        let in_schema = self.schema.as_ref().
+            map_or(false, |schema| schema.iter().any(|column| column.0 == key));
+        let in_counts = self.column_counts.as_ref().
+            map_or(false, |counts| counts.contains_key(key));
+        Some(in_schema || in_counts)
+    }
+
+    // The number of rows written to the sidecar file by `close()`,
+    // or `None` unless `write_row_count()` was enabled
+    pub fn declared_rows(&self) -> Result<Option<u64>, TableImportChunkError> {
+        let mut meta = match File::open(meta_path(self.file_path.as_str())) {
+            Ok(meta) => meta,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(TableImportChunkError::from(err))
+        };
+        let mut rows = String::new();
+        try!(meta.read_to_string(&mut rows));
+        rows.trim().parse().map(Some).map_err(|_|
+            TableImportChunkError::VerificationError(
+                format!("Invalid row count in the sidecar file: {}", rows)))
+    }
+
+    // Starts a new chunk that overwrites this chunk's file, with the framing it was written
+    // with. The file is truncated right away, so anything still reading it by `file_path`,
+    // e.g. an upload on another thread, must be finished before calling this
+    pub fn reset(self) -> Result<TableImportWritableChunk, TableImportChunkError> {
+        if let Err(err) = ::std::fs::remove_file(meta_path(self.file_path.as_str())) {
+            if err.kind() != ErrorKind::NotFound {
+                return Err(TableImportChunkError::from(err))
+            }
+        }
+        TableImportWritableChunk::open(self.file_path, self.tmp_dir, self.framing,
+                                       self.buffer_capacity, self.codec)
+    }
+
+    // The MessagePack in the file, decompressed
+    fn open_decoded(&self) -> Result<Box<Read>, TableImportChunkError> {
+        let file = try!(File::open(self.file_path.as_str()));
+        Ok(match self.codec {
+            ChunkCodec::Gzip => Box::new(try!(GzDecoder::new(file))),
+            ChunkCodec::Uncompressed => Box::new(file)
         })
     }
+
+    fn verify(&self, framing: FramingMode, expected_rows: u64)
+        -> Result<(), TableImportChunkError> {
+        let mut d = try!(self.open_decoded());
+        let mut rows = 0;
+        let mut array_read = false;
+        loop {
+            match ::rmpv::decode::read_value(&mut d) {
+                Ok(::rmpv::Value::Map(_)) if framing == FramingMode::MapStream => rows += 1,
+                Ok(::rmpv::Value::Array(xs)) if framing != FramingMode::MapStream && !array_read => {
+                    array_read = true;
+                    for x in xs {
+                        match x {
+                            ::rmpv::Value::Map(_) => rows += 1,
+                            unexpected =>
+                                return Err(TableImportChunkError::VerificationError(
+                                        format!("Unexpected value in row {}: {:?}",
+                                                rows, unexpected)))
+                        }
+                    }
+                },
+                Ok(unexpected) =>
+                    return Err(TableImportChunkError::VerificationError(
+                            format!("Unexpected value in row {}: {:?}", rows, unexpected))),
+                Err(::rmpv::decode::Error::InvalidMarkerRead(ref err))
+                    if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) =>
+                    return Err(TableImportChunkError::VerificationError(
+                            format!("Failed to decode row {}: {}", rows, err)))
+            }
+        }
+        if rows != expected_rows {
+            return Err(TableImportChunkError::VerificationError(
+                    format!("written rows:{}, read rows:{}", expected_rows, rows)))
+        }
+        Ok(())
+    }
+}
+
+// Reads a gzipped chunk file through to the end, which fails unless the gzip stream is
+// complete and its checksum matches, e.g. for a file staged by another process.
+// Returns the number of the bytes of MessagePack in it, i.e. its uncompressed size
+pub fn validate_gzip_file(path: &Path) -> Result<u64, TableImportChunkError> {
+    let invalid = |err: io::Error| TableImportChunkError::VerificationError(
+        format!("{}: {}", path.display(), err));
+    let file = try!(File::open(path));
+    let mut d = try!(GzDecoder::new(file).map_err(&invalid));
+    io::copy(&mut d, &mut io::sink()).map_err(&invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use table_import::*;
+    use model::SchemaType;
+    use std::collections::HashSet;
+
+    #[test]
+    fn close_verified() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.write_key_and_str("name", "foo").unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        chunk.close_verified().unwrap();
+
+        let chunk = TableImportWritableChunk::new().unwrap();
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn close_verified_fails_on_broken_chunk() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.rows += 1;
+        match chunk.close_verified() {
+            Err(TableImportChunkError::VerificationError(ref x)) =>
+                assert_eq!("written rows:2, read rows:1", x),
+            Err(x) => panic!("unexpected error: {:?}", x),
+            Ok(_) => panic!("a broken chunk is verified")
+        }
+
+        let chunk = TableImportWritableChunk::new().unwrap().close().unwrap();
+        {
+            let mut e = GzEncoder::new(File::create(chunk.file_path.as_str()).unwrap(),
+                                       Compression::Default);
+            write_str(&mut e, "time").unwrap();
+            e.finish().unwrap();
+        }
+        match chunk.verify(FramingMode::MapStream, 0) {
+            Err(TableImportChunkError::VerificationError(ref x)) =>
+                assert!(x.starts_with("Unexpected value in row 0"), "{}", x),
+            x => panic!("unexpected result: {:?}", x)
+        }
+    }
+
+    #[test]
+    fn array_framing() {
+        let mut chunk = TableImportWritableChunk::new_with_framing(FramingMode::Array(2)).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        assert!(chunk.next_row(1).is_err());
+        chunk.close_verified().unwrap();
+
+        let mut chunk = TableImportWritableChunk::new_with_framing(FramingMode::Array(2)).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        assert!(chunk.close().is_err());
+    }
+
+    #[test]
+    fn new_named() {
+        let chunk = TableImportWritableChunk::new_named("part-0001.msgpack.gz").unwrap();
+        assert!(chunk.close().unwrap().file_path.ends_with("/part-0001.msgpack.gz"));
+
+        assert!(TableImportWritableChunk::new_named("../evil.msgpack.gz").is_err());
+        assert!(TableImportWritableChunk::new_named("").is_err());
+    }
+
+    #[test]
+    fn unique_file_names() {
+        let mut names = HashSet::new();
+        for _ in 0..100 {
+            let chunk = TableImportWritableChunk::new().unwrap();
+            let path = ::std::path::Path::new(chunk.file_path.as_str()).to_path_buf();
+            assert!(path.to_str().unwrap().ends_with(".msgpack.gz"));
+            assert!(names.insert(path.file_name().unwrap().to_os_string()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn path_error() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"/tmp/chunk-\xff.msgpack.gz"));
+        match path_to_string(path) {
+            Err(TableImportChunkError::PathError(ref x)) => assert_eq!(path, x.as_path()),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        assert_eq!("/tmp/chunk.msgpack.gz",
+                   path_to_string(Path::new("/tmp/chunk.msgpack.gz")).unwrap());
+    }
+
+    #[test]
+    fn reset() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        let file_path = chunk.file_path.clone();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        let mut chunk = chunk.reset().unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(file_path, chunk.file_path);
+
+        let chunk = chunk.reset().unwrap();
+        assert_eq!(file_path, chunk.file_path);
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn reset_keeps_framing() {
+        let mut chunk = TableImportWritableChunk::new_with_framing(FramingMode::Array(1)).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        let mut chunk = chunk.reset().unwrap();
+        assert_eq!(FramingMode::Array(1), chunk.framing);
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        let chunk = chunk.close_verified().unwrap();
+
+        let mut chunk = chunk.reset().unwrap();
+        assert_eq!(FramingMode::Array(1), chunk.framing);
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000002).unwrap();
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn row_sink() {
+        fn write_rows<S: RowSink>(sink: &mut S) -> Result<(), TableImportChunkError> {
+            try!(sink.begin_row(3));
+            try!(sink.write_field("time", &Value::Integer(Integer::I64(1470000000))));
+            try!(sink.write_field("name", &Value::String("foo".to_string())));
+            try!(sink.write_field("tags", &Value::Array(vec![Value::String("a".to_string()),
+                                                             Value::Nil])));
+            sink.end_row()
+        }
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        write_rows(&mut chunk).unwrap();
+        chunk.close_verified().unwrap();
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.begin_row(2).unwrap();
+        chunk.write_field("time", &Value::Integer(Integer::I64(1470000000))).unwrap();
+        assert!(chunk.end_row().is_err());
+    }
+
+    #[test]
+    fn mock_sink() {
+        let mut sink = MockSink::new();
+        sink.begin_row(2).unwrap();
+        sink.write_field("time", &Value::Integer(Integer::I64(1470000000))).unwrap();
+        sink.write_field("name", &Value::String("foo".to_string())).unwrap();
+        sink.end_row().unwrap();
+        sink.assert_row_count(1);
+        sink.assert_row(0, &[("time", Value::Integer(Integer::I64(1470000000))),
+                             ("name", Value::String("foo".to_string()))]);
+        assert_eq!(4, sink.calls().len());
+        assert_eq!(SinkCall::BeginRow(2), sink.calls()[0]);
+
+        sink.begin_row(2).unwrap();
+        sink.write_field("time", &Value::Integer(Integer::I64(1470000001))).unwrap();
+        assert!(sink.end_row().is_err());
+        assert!(sink.write_field("name", &Value::Nil).is_err());
+        sink.assert_row_count(1);
+    }
+
+    #[test]
+    fn tee_chunk() {
+        let mut tee = TeeChunk::new(
+            TableImportWritableChunk::new().unwrap(),
+            TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap());
+        for i in 0..3 {
+            tee.next_row(3).unwrap();
+            tee.write_key_and_i64("time", 1470000000 + i).unwrap();
+            tee.write_key_and_str("name", "foo").unwrap();
+            tee.write_key_and_nil("note").unwrap();
+            tee.end_row().unwrap();
+        }
+        tee.next_row(5).unwrap();
+        tee.write_key_and_u8("time", 1).unwrap();
+        tee.write_key_and_uuid_str("id", "123e4567-e89b-12d3-a456-426655440000").unwrap();
+        tee.write_key_and_geopoint("at", 35.0, 139.0).unwrap();
+        tee.write_key_and_str_stream("text", &mut "abc".as_bytes(), 3).unwrap();
+        tee.write_key_and_event_time("event", 1470000000, 1).unwrap();
+        tee.end_row().unwrap();
+        tee.next_row(1).unwrap();
+        assert!(tee.write_key_and_pfix("time", 128).is_err());
+        assert!(tee.write_key_and_geopoint("at", 91.0, 0.0).is_err());
+        tee.write_key_and_nil("time").unwrap();
+        tee.end_row().unwrap();
+        assert_eq!(5, tee.first().rows());
+        let (upload, copy) = tee.into_inner();
+        let upload = upload.close().unwrap();
+
+        let mut staged = Vec::new();
+        ::flate2::read::GzDecoder::new(File::open(upload.file_path.as_str()).unwrap()).unwrap().
+            read_to_end(&mut staged).unwrap();
+        let mut kept = Vec::new();
+        ::flate2::read::GzDecoder::new(&copy.finish_bytes().unwrap().bytes[..]).unwrap().
+            read_to_end(&mut kept).unwrap();
+        assert_eq!(staged, kept);
+    }
+
+    #[test]
+    fn write_key_and_uuid_str() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_uuid_str("id", "123e4567-e89b-12d3-a456-426655440000").unwrap();
+        chunk.close_verified().unwrap();
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        assert!(chunk.write_key_and_uuid_str("id", "123e4567e89b12d3a456426655440000").is_err());
+        assert!(chunk.write_key_and_uuid_str("id", "123e4567-e89b-12d3-a456-42665544000g").is_err());
+    }
+
+    #[test]
+    fn write_key_and_str_stream() {
+        let text = "日本語 text ".repeat(1000);
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_str_stream("doc", &mut text.as_bytes(), text.len() as u32).unwrap();
+        chunk.close_verified().unwrap();
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        assert!(chunk.write_key_and_str_stream("doc", &mut "short".as_bytes(), 10).is_err());
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        assert!(chunk.write_key_and_str_stream("doc", &mut &[0x61u8, 0xff][..], 2).is_err());
+    }
+
+    #[test]
+    fn start_row() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        {
+            let mut row = chunk.start_row(2).unwrap();
+            row.write_key_and_i64("time", 1470000000).unwrap();
+            row.write_key_and_str("name", "foo").unwrap();
+            row.finish_row().unwrap();
+        }
+        {
+            let mut row = chunk.start_row(2).unwrap();
+            row.write_key_and_i64("time", 1470000001).unwrap();
+            assert!(row.finish_row().is_err());
+        }
+    }
+
+    #[test]
+    fn inferred_schema() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.infer_schema(true);
+        chunk.next_row(3).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.write_key_and_i32("score", 42).unwrap();
+        chunk.write_key_and_str("name", "foo").unwrap();
+        chunk.next_row(3).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        chunk.write_key_and_f64("score", 4.2).unwrap();
+        chunk.write_key_and_i32("name", 1).unwrap();
+        let chunk = chunk.close().unwrap();
+        assert_eq!(vec![("time".to_string(), SchemaType::Long),
+                        ("score".to_string(), SchemaType::Double),
+                        ("name".to_string(), SchemaType::String)],
+                   chunk.inferred_schema());
+    }
+
+    #[test]
+    fn inferred_schema_skips_failed_write() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.infer_schema(true);
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        match chunk.write_key_and_str("name", "foo") {
+            Err(TableImportChunkError::UnmatchElementNums(_)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        assert_eq!(vec![("time".to_string(), SchemaType::Long)], chunk.inferred_schema());
+    }
+
+    #[test]
+    fn unmatch_element_nums_with_keys() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.track_keys(true);
+        chunk.next_row(3).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.write_key_and_str("name", "foo").unwrap();
+        match chunk.next_row(1) {
+            Err(err) => assert_eq!("capacity:3, added:2, keys:[time, name]", err.to_string()),
+            Ok(_) => panic!("next_row() should fail")
+        }
+    }
+
+    #[test]
+    fn new_with_buffer_capacity() {
+        let mut chunk = TableImportWritableChunk::new_with_buffer_capacity(1).unwrap();
+        for i in 0..100 {
+            chunk.next_row(1).unwrap();
+            chunk.write_key_and_i64("time", 1470000000 + i).unwrap();
+        }
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn reject_text_as_bin() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_bin("text", b"caf\xc3\xa9").unwrap();
+        chunk.reject_text_as_bin(true);
+        match chunk.write_key_and_bin("text", b"caf\xc3\xa9") {
+            Err(TableImportChunkError::TextAsBinary(ref key)) if key == "text" => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        chunk.write_key_and_bin("digest", b"\xd4\x1d\x8c\xd9").unwrap();
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn write_key_and_str_lossy() {
+        use std::fs::File;
+        use flate2::read::GzDecoder;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_str_lossy("message", b"caf\xe9!").unwrap();
+        let chunk = chunk.close().unwrap();
+
+        let mut decoder = GzDecoder::new(File::open(chunk.file_path.as_str()).unwrap()).unwrap();
+        let row = ::rmpv::decode::read_value(&mut decoder).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("message"), Value::from("caf\u{fffd}!"))]), row);
+    }
+
+    #[test]
+    fn multi_chunk_writer() {
+        let mut writer = MultiChunkWriter::new(2).unwrap();
+        for i in 0..3 {
+            writer.next_row(2).unwrap();
+            writer.write_key_and_i64("time", 1470000000 + i).unwrap();
+            writer.write_key_and_str("name", "foo").unwrap();
+        }
+        assert_eq!(1, writer.drain_ready().len());
+        writer.next_row(2).unwrap();
+        writer.write_key_and_i64("time", 1470000003).unwrap();
+        writer.write_key_and_str("name", "bar").unwrap();
+        writer.next_row(1).unwrap();
+        writer.write_key_and_i64("time", 1470000004).unwrap();
+        assert_eq!(2, writer.finish().unwrap().len());
+    }
+
+    #[test]
+    fn row_in_progress() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.track_keys(true);
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        assert!(chunk.row_in_progress());
+        match chunk.flush_final() {
+            Err(ref err @ TableImportChunkError::RowInProgress(..)) =>
+                assert_eq!("The chunk can't be sealed while a row is partially written \
+                            (capacity:2, added:1, keys:[time])", err.to_string()),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        match chunk.close() {
+            Err(TableImportChunkError::RowInProgress(..)) => (),
+            Err(x) => panic!("unexpected error: {:?}", x),
+            Ok(_) => panic!("a chunk with a partial row is closed")
+        }
+
+        let mut writer = MultiChunkWriter::new(1).unwrap();
+        writer.next_row(2).unwrap();
+        writer.write_key_and_i64("time", 1470000000).unwrap();
+        match writer.next_row(2) {
+            Err(TableImportChunkError::RowInProgress(..)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        writer.write_key_and_str("name", "foo").unwrap();
+        writer.next_row(1).unwrap();
+        writer.write_key_and_i64("time", 1470000001).unwrap();
+        let chunks = writer.finish().unwrap();
+        assert_eq!(vec![1, 1], chunks.iter().map(|chunk| chunk.rows).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn on_row() {
+        use std::sync::{Arc, Mutex};
+
+        let counts = Arc::new(Mutex::new(Vec::new()));
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        let counts_in_callback = counts.clone();
+        chunk.on_row(move |rows| counts_in_callback.lock().unwrap().push(rows));
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        assert_eq!(vec![1], *counts.lock().unwrap());
+        chunk.finish_row().unwrap();
+        chunk.next_row(0).unwrap();
+        chunk.close_verified().unwrap();
+        assert_eq!(vec![1, 2, 3], *counts.lock().unwrap());
+    }
+
+    #[test]
+    fn close_durable() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.write_row_count(true);
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        let chunk = chunk.close_durable().unwrap();
+        chunk.verify(FramingMode::MapStream, 1).unwrap();
+        assert_eq!(Some(1), chunk.declared_rows().unwrap());
+    }
+
+    #[test]
+    fn finish_bytes() {
+        use std::io::Cursor;
+        use flate2::read::GzDecoder;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        assert_eq!(1, chunk.rows());
+        let finished = chunk.finish_bytes().unwrap();
+        assert_eq!(1, finished.row_count);
+        assert_eq!(15, finished.uncompressed_bytes);
+
+        let mut decoder = GzDecoder::new(Cursor::new(finished.bytes)).unwrap();
+        let row = ::rmpv::decode::read_value(&mut decoder).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("time"), Value::from(1470000000))]), row);
+
+        let chunk = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        assert!(chunk.close().is_err());
+    }
+
+    #[test]
+    fn write_key_and_char_usize_isize() {
+        use std::io::Cursor;
+        use flate2::read::GzDecoder;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        chunk.next_row(4).unwrap();
+        chunk.write_key_and_char("ascii", 'a').unwrap();
+        chunk.write_key_and_char("kana", '\u{3042}').unwrap();
+        chunk.write_key_and_usize("len", 42).unwrap();
+        chunk.write_key_and_isize("offset", -42).unwrap();
+        let bytes = chunk.finish_bytes().unwrap().bytes;
+
+        let mut decoder = GzDecoder::new(Cursor::new(bytes)).unwrap();
+        let row = ::rmpv::decode::read_value(&mut decoder).unwrap();
+        assert_eq!(Value::Map(vec![
+            (Value::from("ascii"), Value::from("a")),
+            (Value::from("kana"), Value::from("\u{3042}")),
+            (Value::from("len"), Value::from(42u64)),
+            (Value::from("offset"), Value::from(-42i64))
+        ]), row);
+    }
+
+    #[test]
+    fn write_key_and_arrays() {
+        use std::fs::File;
+        use flate2::read::GzDecoder;
+        use rmpv;
+        use value::{Integer, Value};
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.infer_schema(true);
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_bool_array("flags", &[true, false]).unwrap();
+        chunk.write_key_and_value_array("mixed", &[Value::Integer(Integer::I64(1)),
+                                                     Value::String("a".to_string())]).unwrap();
+        let chunk = chunk.close().unwrap();
+        assert_eq!(vec![("mixed".to_string(), SchemaType::Array(Box::new(SchemaType::String)))],
+                   chunk.inferred_schema());
+
+        let mut decoder = GzDecoder::new(File::open(chunk.file_path.as_str()).unwrap()).unwrap();
+        let row = rmpv::decode::read_value(&mut decoder).unwrap();
+        assert_eq!(rmpv::Value::Map(vec![
+            (rmpv::Value::from("flags"),
+             rmpv::Value::Array(vec![rmpv::Value::from(true), rmpv::Value::from(false)])),
+            (rmpv::Value::from("mixed"),
+             rmpv::Value::Array(vec![rmpv::Value::from(1), rmpv::Value::from("a")]))
+        ]), row);
+    }
+
+    #[test]
+    fn write_key_and_json() {
+        use std::fs::File;
+        use rmpv::Value;
+        use rustc_serialize::json::Json;
+
+        let json = Json::from_str(r#"{"browser": {"name": "Firefox", "version": 52}}"#).unwrap();
+        let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+        chunk.infer_schema(true);
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_json("agent", &json).unwrap();
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(vec![("agent".to_string(), SchemaType::String)], chunk.inferred_schema());
+
+        let mut file = File::open(chunk.file_path.as_str()).unwrap();
+        let row = ::rmpv::decode::read_value(&mut file).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("agent"),
+                                    Value::from(r#"{"browser":{"name":"Firefox","version":52}}"#))]),
+                   row);
+    }
+
+    #[test]
+    fn write_key_and_geopoint() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        match chunk.write_key_and_geopoint("location", 91.0, 0.0) {
+            Err(TableImportChunkError::ValueOutOfRange(_)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        chunk.write_key_and_geopoint("location", 35.68, 139.76).unwrap();
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn write_key_and_fixint() {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+        chunk.next_row(4).unwrap();
+        chunk.write_key_and_pfix("a", 0).unwrap();
+        chunk.write_key_and_pfix("b", 127).unwrap();
+        chunk.write_key_and_nfix("c", -1).unwrap();
+        chunk.write_key_and_nfix("d", -32).unwrap();
+        for &val in [128, 200, 255].iter() {
+            match chunk.write_key_and_pfix("e", val) {
+                Err(TableImportChunkError::ValueOutOfRange(_)) => (),
+                x => panic!("unexpected result for {}: {:?}", val, x)
+            }
+        }
+        for &val in [0, 1, -33, -128].iter() {
+            match chunk.write_key_and_nfix("e", val) {
+                Err(TableImportChunkError::ValueOutOfRange(_)) => (),
+                x => panic!("unexpected result for {}: {:?}", val, x)
+            }
+        }
+        let chunk = chunk.close_verified().unwrap();
+
+        let mut bytes = Vec::new();
+        File::open(chunk.file_path.as_str()).unwrap().read_to_end(&mut bytes).unwrap();
+        assert_eq!(vec![0x84, 0xa1, b'a', 0x00, 0xa1, b'b', 0x7f,
+                        0xa1, b'c', 0xff, 0xa1, b'd', 0xe0],
+                   bytes);
+    }
+
+    #[test]
+    fn flush_final() {
+        use std::io::Cursor;
+        use rustc_serialize::json::Json;
+        use value::Value;
+
+        type Write = Box<Fn(&mut TableImportWritableChunk) -> Result<(), TableImportChunkError>>;
+        let writes: Vec<Write> = vec![
+            Box::new(|c| c.next_row(1)),
+            Box::new(|c| c.write_key_and_array_header("k", 0)),
+            Box::new(|c| c.write_key_and_bin("k", b"")),
+            Box::new(|c| c.write_key_and_bool("k", true)),
+            Box::new(|c| c.write_key_and_bool_array("k", &[true])),
+            Box::new(|c| c.write_key_and_char("k", 'a')),
+            Box::new(|c| c.write_key_and_event_time("k", 0, 0)),
+            Box::new(|c| c.write_key_and_ext("k", 1, b"")),
+            Box::new(|c| c.write_key_and_ext_meta("k", 0, 1)),
+            Box::new(|c| c.write_key_and_f32("k", 0.0)),
+            Box::new(|c| c.write_key_and_f64("k", 0.0)),
+            Box::new(|c| c.write_key_and_geopoint("k", 0.0, 0.0)),
+            Box::new(|c| c.write_key_and_i16("k", 0)),
+            Box::new(|c| c.write_key_and_i32("k", 0)),
+            Box::new(|c| c.write_key_and_i64("k", 0)),
+            Box::new(|c| c.write_key_and_i8("k", 0)),
+            Box::new(|c| c.write_key_and_isize("k", 0)),
+            Box::new(|c| c.write_key_and_json("k", &Json::Null)),
+            Box::new(|c| c.write_key_and_map_len("k", 0)),
+            Box::new(|c| c.write_key_and_nfix("k", -1)),
+            Box::new(|c| c.write_key_and_nil("k")),
+            Box::new(|c| c.write_key_and_pfix("k", 0)),
+            Box::new(|c| c.write_key_and_sint("k", 0)),
+            Box::new(|c| c.write_key_and_sint_eff("k", 0)),
+            Box::new(|c| c.write_key_and_str("k", "")),
+            Box::new(|c| c.write_key_and_str_lossy("k", b"")),
+            Box::new(|c| c.write_key_and_str_stream("k", &mut Cursor::new(b"a"), 1)),
+            Box::new(|c| c.write_key_and_u16("k", 0)),
+            Box::new(|c| c.write_key_and_u32("k", 0)),
+            Box::new(|c| c.write_key_and_u64("k", 0)),
+            Box::new(|c| c.write_key_and_u8("k", 0)),
+            Box::new(|c| c.write_key_and_uint("k", 0)),
+            Box::new(|c| c.write_key_and_usize("k", 0)),
+            Box::new(|c| c.write_key_and_uuid("k", &[0; 16])),
+            Box::new(|c| c.write_key_and_uuid_str("k", "00000000-0000-0000-0000-000000000000")),
+            Box::new(|c| c.write_key_and_value("k", &Value::Nil)),
+            Box::new(|c| c.write_key_and_value_array("k", &[Value::Nil])),
+            Box::new(|c| c.write_encoded_key_and_f64(&EncodedKey::new("k"), 0.0)),
+            Box::new(|c| c.write_encoded_key_and_i64(&EncodedKey::new("k"), 0)),
+            Box::new(|c| c.write_encoded_key_and_str(&EncodedKey::new("k"), "")),
+            Box::new(|c| c.write_encoded_key_and_value(&EncodedKey::new("k"), &Value::Nil))
+        ];
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.flush_final().unwrap();
+        for write in writes.iter() {
+            match write(&mut chunk) {
+                Err(TableImportChunkError::AlreadyClosed) => (),
+                x => panic!("unexpected result: {:?}", x)
+            }
+        }
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn column_fill_rates() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.track_column_stats(true);
+        for i in 0..4 {
+            chunk.next_row(2).unwrap();
+            chunk.write_key_and_i64("time", 1470000000 + i).unwrap();
+            if i == 0 {
+                chunk.write_key_and_str("name", "foo").unwrap();
+            }
+            else {
+                chunk.write_key_and_nil("name").unwrap();
+            }
+        }
+        let chunk = chunk.close().unwrap();
+        let rates = chunk.column_fill_rates();
+        assert_eq!(Some(&1.0), rates.get("time"));
+        assert_eq!(Some(&0.25), rates.get("name"));
+    }
+
+    #[test]
+    fn with_schema() {
+        use std::fs::File;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::with_schema(
+            &[("time", SchemaType::Long), ("name", SchemaType::String),
+              ("score", SchemaType::Double)]).unwrap();
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        match chunk.write_key_and_str("nmae", "foo") {
+            Err(TableImportChunkError::UndeclaredColumn(ref key)) if key == "nmae" => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        match chunk.write_key_and_str("score", "high") {
+            Err(TableImportChunkError::SchemaMismatch(..)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        chunk.write_key_and_i64("score", 3).unwrap();
+        chunk.finish_row().unwrap();
+        chunk.close_verified().unwrap();
+
+        let mut chunk = TableImportWritableChunk::with_schema(
+            &[("time", SchemaType::Long), ("name", SchemaType::String)]).unwrap();
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        assert!(chunk.write_key_and_i64("name", 1).is_err());
+        chunk.finish_row().unwrap();
+        let chunk = chunk.close_verified().unwrap();
+
+        let file = File::open(chunk.file_path.as_str()).unwrap();
+        let mut d = ::flate2::read::GzDecoder::new(file).unwrap();
+        let row = ::rmpv::decode::read_value(&mut d).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("time"), Value::from(1470000000)),
+                                   (Value::from("name"), Value::Nil)]), row);
+    }
+
+    #[test]
+    fn abandon_row() {
+        use std::fs::File;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::with_schema(
+            &[("time", SchemaType::Long), ("name", SchemaType::String)]).unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.write_key_and_str("name", "foo").unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        match chunk.abandon_row() {
+            Err(TableImportChunkError::UnexpectedError(_)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+
+        let mut chunk = chunk.reset().unwrap();
+        chunk.hold_rows(true);
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.write_key_and_str("name", "foo").unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        assert!(chunk.write_key_and_i64("name", 1).is_err());
+        chunk.abandon_row().unwrap();
+        assert!(!chunk.row_in_progress());
+        // A finished row isn't taken back
+        chunk.abandon_row().unwrap();
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1470000002).unwrap();
+        chunk.abandon_row().unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000003).unwrap();
+        chunk.write_key_and_str("name", "bar").unwrap();
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(2, chunk.rows);
+
+        let file = File::open(chunk.file_path.as_str()).unwrap();
+        let mut d = ::flate2::read::GzDecoder::new(file).unwrap();
+        for &(time, name) in &[(1470000000, "foo"), (1470000003, "bar")] {
+            assert_eq!(Value::Map(vec![(Value::from("time"), Value::from(time)),
+                                       (Value::from("name"), Value::from(name))]),
+                       ::rmpv::decode::read_value(&mut d).unwrap());
+        }
+    }
+
+    #[test]
+    fn with_schema_fixed_length_row() {
+        use std::fs::File;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::with_schema(
+            &[("time", SchemaType::Long), ("name", SchemaType::String)]).unwrap();
+        chunk.infer_schema(true);
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        match chunk.write_key_and_str("nmae", "foo") {
+            Err(TableImportChunkError::UndeclaredColumn(ref key)) if key == "nmae" => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        match chunk.write_key_and_i64("name", 1) {
+            Err(TableImportChunkError::SchemaMismatch(..)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        chunk.write_key_and_str("name", "foo").unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000001).unwrap();
+        chunk.write_key_and_str("name", "bar").unwrap();
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(vec![("time".to_string(), SchemaType::Long),
+                        ("name".to_string(), SchemaType::String)], chunk.inferred_schema());
+
+        let file = File::open(chunk.file_path.as_str()).unwrap();
+        let mut d = ::flate2::read::GzDecoder::new(file).unwrap();
+        let row = ::rmpv::decode::read_value(&mut d).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("time"), Value::from(1470000000)),
+                                   (Value::from("name"), Value::from("foo"))]), row);
+    }
+
+    #[test]
+    fn write_f64_row() {
+        let keys = ["cpu", "mem", "disk"];
+        let mut fast = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        let mut plain = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        for i in 0..10 {
+            let values = [i as f64, i as f64 / 2.0, i as f64 * 2.0];
+            fast.write_f64_row(&keys, &values).unwrap();
+            plain.next_row(3).unwrap();
+            for (key, value) in keys.iter().zip(values.iter()) {
+                plain.write_key_and_f64(key, *value).unwrap();
+            }
+        }
+        assert!(fast.write_f64_row(&keys, &[1.0]).is_err());
+        assert_eq!(plain.finish_bytes().unwrap(), fast.finish_bytes().unwrap());
+
+        let mut tracked = TableImportWritableChunk::new().unwrap();
+        tracked.infer_schema(true);
+        tracked.write_f64_row(&keys, &[1.0, 2.0, 3.0]).unwrap();
+        assert_eq!(3, tracked.inferred_schema().len());
+        tracked.close_verified().unwrap();
+    }
+
+    #[test]
+    fn write_row_dyn() {
+        let mut dyn_chunk = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        let name = "foo".to_string();
+        let missing: Option<i64> = None;
+        dyn_chunk.write_row_dyn(&[("time", &1470000000i64), ("id", &42u64), ("ratio", &0.5f64),
+                                  ("ok", &true), ("tag", &"t"), ("name", &name),
+                                  ("data", &vec![1u8, 2]), ("score", &Some(3i64)),
+                                  ("missing", &missing)]).unwrap();
+
+        let mut plain = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        plain.next_row(9).unwrap();
+        plain.write_key_and_i64("time", 1470000000).unwrap();
+        plain.write_key_and_u64("id", 42).unwrap();
+        plain.write_key_and_f64("ratio", 0.5).unwrap();
+        plain.write_key_and_bool("ok", true).unwrap();
+        plain.write_key_and_str("tag", "t").unwrap();
+        plain.write_key_and_str("name", "foo").unwrap();
+        plain.write_key_and_bin("data", &[1, 2]).unwrap();
+        plain.write_key_and_i64("score", 3).unwrap();
+        plain.write_key_and_nil("missing").unwrap();
+        assert_eq!(plain.finish_bytes().unwrap(), dyn_chunk.finish_bytes().unwrap());
+    }
+
+    #[test]
+    fn assume_time_present() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row_with_time(1, 1470000000).unwrap();
+        chunk.write_key_and_str("name", "foo").unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_current_time().unwrap();
+        chunk.write_key_and_str("name", "bar").unwrap();
+        chunk.close_verified().unwrap();
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.assume_time_present(true);
+        match chunk.next_row_with_time(1, 1470000000) {
+            Err(TableImportChunkError::TimeColumnPresent) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        chunk.next_row(1).unwrap();
+        match chunk.write_current_time() {
+            Err(TableImportChunkError::TimeColumnPresent) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.close_verified().unwrap();
+    }
+
+    #[test]
+    fn write_key_and_event_time() {
+        use std::fs::File;
+        use flate2::read::GzDecoder;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(1).unwrap();
+        assert!(chunk.write_key_and_event_time("time", 1470000000, 1_000_000_000).is_err());
+        chunk.write_key_and_event_time("time", 0x5799_2c80, 0x0102_0304).unwrap();
+        let chunk = chunk.close().unwrap();
+
+        let mut decoder = GzDecoder::new(File::open(chunk.file_path.as_str()).unwrap()).unwrap();
+        let row = ::rmpv::decode::read_value(&mut decoder).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("time"),
+                                    Value::Ext(0, vec![0x57, 0x99, 0x2c, 0x80, 1, 2, 3, 4]))]),
+                   row);
+    }
+
+    #[test]
+    fn nested_timestamps() {
+        use std::io::Cursor;
+        use flate2::read::GzDecoder;
+        use chrono::{TimeZone, UTC};
+
+        let time = UTC.ymd(2016, 8, 1).and_hms(0, 0, 0);
+        let event = Value::Map(vec![
+            (Value::String("occurred_at".to_string()), Value::timestamp(&time)),
+            (Value::String("received_at".to_string()),
+             Value::event_time(time.timestamp() as u32, 5).unwrap())]);
+        assert!(Value::event_time(0, 1_000_000_000).is_none());
+
+        let mut chunk = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_timestamp("time", &time).unwrap();
+        chunk.write_key_and_value("event", &event).unwrap();
+        let bytes = chunk.finish_bytes().unwrap().bytes;
+
+        let mut decoder = GzDecoder::new(Cursor::new(bytes)).unwrap();
+        let row = Value::from(::rmpv::decode::read_value(&mut decoder).unwrap());
+        assert_eq!(Value::Map(vec![
+            (Value::String("time".to_string()), Value::Integer(Integer::I64(1470009600))),
+            (Value::String("event".to_string()), Value::Map(vec![
+                (Value::String("occurred_at".to_string()), Value::Integer(Integer::I64(1470009600))),
+                (Value::String("received_at".to_string()),
+                 Value::Ext(0, vec![0x57, 0x9e, 0x91, 0x00, 0, 0, 0, 5]))]))]), row);
+    }
+
+    #[test]
+    fn write_key_and_ext() {
+        use std::fs::File;
+        use flate2::read::GzDecoder;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_ext("payload", 5, &[1, 2, 3]).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        let chunk = chunk.close_verified().unwrap();
+
+        let mut decoder = GzDecoder::new(File::open(chunk.file_path.as_str()).unwrap()).unwrap();
+        let row = ::rmpv::decode::read_value(&mut decoder).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("payload"), Value::Ext(5, vec![1, 2, 3])),
+                                   (Value::from("time"), Value::from(1470000000))]),
+                   row);
+    }
+
+    #[test]
+    fn import_ndjson() {
+        use std::io::Cursor;
+
+        let input = "{\"time\":1470000000,\"path\":\"/\"}\n\n{\"time\":1470000001,\"tags\":[\"a\"]}\n";
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.infer_schema(true);
+        assert_eq!(2, super::import_ndjson(Cursor::new(input), &mut chunk).unwrap());
+        assert_eq!(vec![("path".to_string(), SchemaType::String),
+                        ("time".to_string(), SchemaType::Long),
+                        ("tags".to_string(), SchemaType::Array(Box::new(SchemaType::String)))],
+                   chunk.inferred_schema());
+
+        let input = "{\"time\":1470000002}\n{\"time\":\n[1]\n";
+        match super::import_ndjson(Cursor::new(input), &mut chunk) {
+            Err(TableImportChunkError::InvalidJsonLine(2, _)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(3, chunk.rows);
+    }
+
+    #[test]
+    fn chunks_from() {
+        use value::{Integer, Value};
+
+        let records = (0..5).map(|i| vec![("time".to_string(), Value::Integer(Integer::I64(i)))]);
+        let chunks = super::chunks_from(records, ChunkOptions { rows_per_chunk: 2 }).
+            collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(3, chunks.len());
+
+        let mut chunks = super::chunks_from(Vec::new().into_iter(),
+                                            ChunkOptions { rows_per_chunk: 2 });
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn max_columns() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.max_columns(Some(2));
+        match chunk.next_row(3) {
+            Err(TableImportChunkError::TooManyColumns { len: 3, max: 2 }) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        chunk.next_row(2).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        chunk.write_key_and_str("name", "foo").unwrap();
+        chunk.close().unwrap();
+    }
+
+    #[test]
+    fn scratch_buffer() {
+        use std::fs::File;
+        use rmpv::Value;
+
+        let long = "x".repeat(SCRATCH_CAPACITY * 2);
+        let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+        for i in 0..1000 {
+            chunk.next_row(2).unwrap();
+            chunk.write_key_and_i64("time", i).unwrap();
+            chunk.write_key_and_str("s", if i == 500 { long.as_str() } else { "a" }).unwrap();
+        }
+        let chunk = chunk.close().unwrap();
+
+        let mut file = File::open(chunk.file_path.as_str()).unwrap();
+        for i in 0..1000 {
+            let row = ::rmpv::decode::read_value(&mut file).unwrap();
+            let s = if i == 500 { long.as_str() } else { "a" };
+            assert_eq!(Value::Map(vec![(Value::from("time"), Value::from(i)),
+                                       (Value::from("s"), Value::from(s))]), row);
+        }
+    }
+
+    #[test]
+    fn extend_from_chunk() {
+        use rmpv::Value;
+
+        let mut src = TableImportWritableChunk::new().unwrap();
+        for i in 0..3 {
+            src.next_row(5).unwrap();
+            src.write_key_and_i64("time", 1470000000 + i).unwrap();
+            src.write_key_and_f32("ratio", 0.5).unwrap();
+            src.write_key_and_bin("raw", &[1, 2]).unwrap();
+            src.write_key_and_ext("ext", 3, &[4]).unwrap();
+            src.write_key_and_nil("note").unwrap();
+        }
+        let src = src.close().unwrap();
+
+        let mut merged = TableImportWritableChunk::new().unwrap();
+        merged.next_row(1).unwrap();
+        merged.write_key_and_i64("time", 1460000000).unwrap();
+        assert_eq!(3, merged.extend_from_chunk(&src).unwrap());
+        assert_eq!(3, merged.extend_from_chunk(&src).unwrap());
+        assert_eq!(7, merged.rows());
+        let merged = merged.close_verified().unwrap();
+
+        let mut d = merged.open_decoded().unwrap();
+        ::rmpv::decode::read_value(&mut d).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("time"), Value::from(1470000000)),
+                                   (Value::from("ratio"), Value::F32(0.5)),
+                                   (Value::from("raw"), Value::Binary(vec![1, 2])),
+                                   (Value::from("ext"), Value::Ext(3, vec![4])),
+                                   (Value::from("note"), Value::Nil)]),
+                   ::rmpv::decode::read_value(&mut d).unwrap());
+
+        let mut src_bytes = Vec::new();
+        src.open_decoded().unwrap().read_to_end(&mut src_bytes).unwrap();
+        let mut merged_bytes = Vec::new();
+        merged.open_decoded().unwrap().read_to_end(&mut merged_bytes).unwrap();
+        // The first row is fixmap(1), fixstr "time" and int64, which stays int64 in the copies
+        assert_eq!([&src_bytes[..], &src_bytes[..]].concat(), &merged_bytes[15..]);
+
+        let mut framed = TableImportWritableChunk::new_with_framing(FramingMode::Array(2)).unwrap();
+        for i in 0..2 {
+            framed.next_row(1).unwrap();
+            framed.write_key_and_u16("id", 300 + i).unwrap();
+        }
+        let framed = framed.close().unwrap();
+        let mut framed_bytes = Vec::new();
+        framed.open_decoded().unwrap().read_to_end(&mut framed_bytes).unwrap();
+        let mut copied = TableImportWritableChunk::new().unwrap();
+        assert_eq!(2, copied.extend_from_chunk(&framed).unwrap());
+        let copied = copied.close().unwrap();
+        let mut copied_bytes = Vec::new();
+        copied.open_decoded().unwrap().read_to_end(&mut copied_bytes).unwrap();
+        // Only the fixarray(2) header of the rows isn't copied
+        assert_eq!(&framed_bytes[1..], &copied_bytes[..]);
+    }
+
+    #[test]
+    fn metadata() {
+        let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        let chunk = chunk.close().unwrap();
+
+        let metadata = chunk.metadata().unwrap();
+        assert_eq!(chunk.file_path, metadata.path);
+        assert_eq!(1, metadata.row_count);
+        assert_eq!(ChunkCodec::Uncompressed, metadata.codec);
+        // fixmap(1), fixstr "time" and int64
+        assert_eq!(15, metadata.uncompressed_bytes);
+        assert_eq!(15, metadata.compressed_bytes);
+        assert_eq!(32, metadata.md5.len());
+    }
+
+    #[test]
+    fn md5_hex_digest() {
+        assert_eq!("d41d8cd98f00b204e9800998ecf8427e", super::md5_hex_digest(&b""[..]).unwrap());
+        assert_eq!("900150983cd24fb0d6963f7d28e17f72", super::md5_hex_digest(&b"abc"[..]).unwrap());
+    }
+
+    #[test]
+    fn uncompressed_chunk() {
+        use std::fs::File;
+        use rmpv::Value;
+
+        let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(ChunkCodec::Uncompressed, chunk.codec());
+        assert!(chunk.file_path.ends_with(".msgpack"));
+
+        let mut file = File::open(chunk.file_path.as_str()).unwrap();
+        let row = ::rmpv::decode::read_value(&mut file).unwrap();
+        assert_eq!(Value::Map(vec![(Value::from("time"), Value::from(1470000000))]), row);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn write_json_values() {
+        let rows = vec![
+            ::serde_json::from_str(r#"{"time":1470000000,"name":"foo","tags":["a","b"]}"#).unwrap(),
+            ::serde_json::from_str(r#"{"time":1470000001,"score":1.5,"note":null}"#).unwrap()
+        ];
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.write_json_values(rows.into_iter()).unwrap();
+        assert_eq!(2, chunk.rows());
+        match chunk.write_json_values(vec![::serde_json::Value::from(42)].into_iter()) {
+            Err(TableImportChunkError::NotAnObject(ref x)) => assert_eq!("42", x),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(2, chunk.rows);
+    }
+
+    #[test]
+    fn next_row_dynamic() {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut chunk = TableImportWritableChunk::new_with_codec(ChunkCodec::Uncompressed).unwrap();
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1).unwrap();
+        assert!(chunk.next_row(1).is_err());
+        chunk.finish_row().unwrap();
+        chunk.next_row_dynamic().unwrap();
+        for i in 0..16 {
+            chunk.write_key_and_i64(format!("c{}", i).as_str(), 1).unwrap();
+        }
+        chunk.finish_row().unwrap();
+        assert!(chunk.finish_row().is_err());
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 2).unwrap();
+        let chunk = chunk.close_verified().unwrap();
+
+        let mut bytes = Vec::new();
+        File::open(chunk.file_path.as_str()).unwrap().read_to_end(&mut bytes).unwrap();
+        // fixmap for 1 field, then map16 for 16 fields
+        assert_eq!(0x81, bytes[0]);
+        assert_eq!(&[0xde, 0x00, 0x10], &bytes[15..18]);
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1).unwrap();
+        match chunk.close() {
+            Err(TableImportChunkError::RowInProgress(..)) => (),
+            Err(x) => panic!("unexpected error: {:?}", x),
+            Ok(_) => panic!("a chunk with an unfinished row is closed")
+        }
+
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.max_columns(Some(1));
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1).unwrap();
+        assert!(chunk.write_key_and_i64("name", 1).is_err());
+    }
+
+    #[test]
+    fn shared_chunk_sink() {
+        use std::sync::Arc;
+        use std::thread;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedChunkSink>();
+
+        let sink = Arc::new(SharedChunkSink::new(10).unwrap());
+        let producers: Vec<_> = (0..4).map(|i| {
+            let sink = sink.clone();
+            thread::spawn(move || {
+                let mut writer = sink.writer().unwrap();
+                for j in 0..25 {
+                    writer.next_row(2).unwrap();
+                    writer.write_key_and_i64("time", 1470000000 + j).unwrap();
+                    writer.write_key_and_i64("producer", i).unwrap();
+                    if j == 12 {
+                        sink.hand_over(&mut writer);
+                    }
+                }
+                sink.finish_writer(writer).unwrap();
+            })
+        }).collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let chunks = sink.take_ready();
+        assert_eq!(12, chunks.len());
+        assert_eq!(100, chunks.iter().map(|chunk| chunk.rows).sum::<u64>());
+        assert!(sink.take_ready().is_empty());
+        assert!(SharedChunkSink::new(0).is_err());
+    }
+
+    #[test]
+    fn declared_rows() {
+        let mut chunk = TableImportWritableChunk::new().unwrap();
+        chunk.write_row_count(true);
+        for i in 0..3 {
+            chunk.next_row(1).unwrap();
+            chunk.write_key_and_i64("time", 1470000000 + i).unwrap();
+        }
+        let chunk = chunk.close().unwrap();
+        assert_eq!(Some(3), chunk.declared_rows().unwrap());
+
+        let chunk = chunk.reset().unwrap().close().unwrap();
+        assert_eq!(None, chunk.declared_rows().unwrap());
+    }
+
+    #[test]
+    fn new_with_max_bytes() {
+        let mut chunk = TableImportWritableChunk::new_with_max_bytes(20).unwrap();
+        chunk.next_row(1).unwrap();
+        chunk.write_key_and_i64("time", 1470000000).unwrap();
+        assert_eq!(15, chunk.written_bytes());
+        chunk.next_row(1).unwrap();
+        match chunk.write_key_and_i64("time", 1470000001) {
+            Err(TableImportChunkError::ChunkFull { bytes: 30, max: 20 }) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        assert_eq!(15, chunk.written_bytes());
+        assert!(!chunk.row_in_progress());
+
+        // A dynamic row is checked as a whole when it's finished
+        chunk.next_row_dynamic().unwrap();
+        chunk.write_key_and_i64("time", 1470000002).unwrap();
+        match chunk.finish_row() {
+            Err(TableImportChunkError::ChunkFull { bytes: 30, max: 20 }) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+        assert_eq!(15, chunk.written_bytes());
+
+        let chunk = chunk.close_verified().unwrap();
+        assert_eq!(1, chunk.rows);
+        assert!(validate_gzip_file(Path::new(&chunk.file_path)).unwrap() <= 20);
+    }
+
+    #[test]
+    fn write_encoded_key() {
+        use value::{Integer, Value};
+
+        let time = EncodedKey::new("time");
+        let score = EncodedKey::new("score");
+        let name = EncodedKey::new("name");
+        let tag = EncodedKey::new("tag");
+        let mut encoded = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        encoded.infer_schema(true);
+        let mut plain = TableImportWritableChunk::new_in_memory(FramingMode::MapStream).unwrap();
+        plain.infer_schema(true);
+        for i in 0..3 {
+            encoded.next_row(4).unwrap();
+            encoded.write_encoded_key_and_i64(&time, 1470000000 + i).unwrap();
+            encoded.write_encoded_key_and_f64(&score, 0.5).unwrap();
+            encoded.write_encoded_key_and_str(&name, "foo").unwrap();
+            encoded.write_encoded_key_and_value(&tag, &Value::Integer(Integer::I64(i))).unwrap();
+            plain.next_row(4).unwrap();
+            plain.write_key_and_i64("time", 1470000000 + i).unwrap();
+            plain.write_key_and_f64("score", 0.5).unwrap();
+            plain.write_key_and_str("name", "foo").unwrap();
+            plain.write_key_and_value("tag", &Value::Integer(Integer::I64(i))).unwrap();
+        }
+        assert_eq!(plain.inferred_schema(), encoded.inferred_schema());
+        assert_eq!(plain.finish_bytes().unwrap(), encoded.finish_bytes().unwrap());
+    }
+
+    #[test]
+    fn error_source() {
+        use std::error::Error;
+        use std::io;
+
+        let err = TableImportChunkError::from(io::Error::new(io::ErrorKind::Other, "disk full"));
+        assert_eq!("disk full", err.source().unwrap().to_string());
+        let err = TableImportChunkError::ColumnWriteError("user_id".to_string(), Box::new(err));
+        assert_eq!("while writing column \"user_id\": disk full", err.to_string());
+        assert_eq!("disk full", err.source().unwrap().to_string());
+        assert!(TableImportChunkError::InvalidUuid("x".to_string()).source().is_none());
+    }
 }
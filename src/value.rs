@@ -23,6 +23,28 @@ pub enum Value {
     Ext(i8, Vec<u8>),
 }
 
+// Timestamps encoded the same way at any depth, e.g. in a nested map of an event
+impl Value {
+    // Unix time in seconds, which is how TD stores `time`
+    pub fn timestamp<Tz: ::chrono::TimeZone>(time: &::chrono::DateTime<Tz>) -> Value {
+        Value::Integer(Integer::I64(time.timestamp()))
+    }
+
+    // Fluentd's EventTime as `write_key_and_event_time()` writes it, or `None` if `nanos`
+    // isn't less than a second
+    pub fn event_time(secs: u32, nanos: u32) -> Option<Value> {
+        if nanos >= 1_000_000_000 {
+            return None
+        }
+        let mut payload = vec![0u8; 8];
+        for i in 0..4 {
+            payload[i] = (secs >> (24 - 8 * i)) as u8;
+            payload[i + 4] = (nanos >> (24 - 8 * i)) as u8;
+        }
+        Some(Value::Ext(0, payload))
+    }
+}
+
 impl From<::rmpv::Value> for Value {
     fn from(src: ::rmpv::Value) -> Value {
         match src {
@@ -50,3 +72,50 @@ impl From<::rmpv::Value> for Value {
         }
     }
 }
+
+impl From<::rustc_serialize::json::Json> for Value {
+    fn from(src: ::rustc_serialize::json::Json) -> Value {
+        match src {
+            ::rustc_serialize::json::Json::Null => Value::Nil,
+            ::rustc_serialize::json::Json::Boolean(x) => Value::Boolean(x),
+            ::rustc_serialize::json::Json::I64(x) => Value::Integer(Integer::I64(x)),
+            ::rustc_serialize::json::Json::U64(x) => Value::Integer(Integer::U64(x)),
+            ::rustc_serialize::json::Json::F64(x) => Value::Float(Float::F64(x)),
+            ::rustc_serialize::json::Json::String(x) => Value::String(x),
+            ::rustc_serialize::json::Json::Array(xs) =>
+                Value::Array(xs.into_iter().map(|x| Value::from(x)).collect()),
+            ::rustc_serialize::json::Json::Object(xs) =>
+                Value::Map(xs.into_iter().map(|(k, v)| {
+                    (Value::String(k), Value::from(v))
+                }).collect())
+        }
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl From<::serde_json::Value> for Value {
+    fn from(src: ::serde_json::Value) -> Value {
+        match src {
+            ::serde_json::Value::Null => Value::Nil,
+            ::serde_json::Value::Bool(x) => Value::Boolean(x),
+            ::serde_json::Value::Number(x) => {
+                if let Some(x) = x.as_i64() {
+                    Value::Integer(Integer::I64(x))
+                }
+                else if let Some(x) = x.as_u64() {
+                    Value::Integer(Integer::U64(x))
+                }
+                else {
+                    Value::Float(Float::F64(x.as_f64().unwrap()))
+                }
+            },
+            ::serde_json::Value::String(x) => Value::String(x),
+            ::serde_json::Value::Array(xs) =>
+                Value::Array(xs.into_iter().map(|x| Value::from(x)).collect()),
+            ::serde_json::Value::Object(xs) =>
+                Value::Map(xs.into_iter().map(|(k, v)| {
+                    (Value::String(k), Value::from(v))
+                }).collect())
+        }
+    }
+}